@@ -0,0 +1,30 @@
+#[path = "util.rs"]
+mod util;
+
+use bitcoin_scanner::{ChainEvent, ChainFollower};
+
+// Prints blocks as the node connects and disconnects them, starting from
+// genesis or after the given block hash. Safe to run next to bitcoind.
+//
+// Usage: cargo run --example follow_chain [block hash]
+pub fn main() {
+    let network = bitcoin::Network::Bitcoin;
+    let data_dir = util::bitcoin_data_dir(network);
+
+    let mut follower = ChainFollower::new(data_dir, network);
+    if let Some(hash) = std::env::args().nth(1) {
+        follower.resume_after(hash.parse().unwrap());
+    }
+
+    for event in follower {
+        match event {
+            Ok(ChainEvent::BlockConnected { height, block, .. }) => {
+                println!("+ {}\t{}", height, block.block_hash())
+            }
+            Ok(ChainEvent::BlockDisconnected { height, block, .. }) => {
+                println!("- {}\t{}", height, block.block_hash())
+            }
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
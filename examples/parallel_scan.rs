@@ -0,0 +1,36 @@
+#[path = "util.rs"]
+mod util;
+
+use bitcoin_scanner::{Pipeline, Scanner};
+
+// Counts transactions and inputs of the whole chain using all cores.
+//
+// Usage: cargo run --release --example parallel_scan [workers]
+pub fn main() {
+    let network = bitcoin::Network::Bitcoin;
+    let data_dir = util::bitcoin_data_dir(network);
+    let mut scanner = Scanner::new(data_dir, network).unwrap();
+
+    let mut pipeline = Pipeline::new();
+    if let Some(workers) = std::env::args().nth(1) {
+        pipeline = pipeline.workers(workers.parse().unwrap());
+    }
+
+    let results = scanner
+        .process_blocks(.., &pipeline, |height, block, _undo| {
+            let inputs: usize = block.txdata.iter().map(|tx| tx.input.len()).sum();
+            (height, block.txdata.len(), inputs)
+        })
+        .unwrap();
+
+    let (mut txs, mut inputs) = (0, 0);
+    for result in results {
+        let (height, block_txs, block_inputs) = result.unwrap();
+        txs += block_txs;
+        inputs += block_inputs;
+        if height % 10_000 == 0 {
+            println!("Height {}: {} transactions, {} inputs", height, txs, inputs);
+        }
+    }
+    println!("Done: {} transactions, {} inputs", txs, inputs);
+}
@@ -26,11 +26,12 @@ struct DotSats<'a> {
 }
 
 pub fn main() {
-    let data_dir = util::bitcoin_data_dir(bitcoin::Network::Bitcoin);
+    let network = bitcoin::Network::Bitcoin;
+    let data_dir = util::bitcoin_data_dir(network);
 
-    let mut scanner = Scanner::new(data_dir);
+    let mut scanner = Scanner::new(data_dir, network).unwrap();
     let tip_hash = scanner.tip_hash;
-    let _tip = scanner.read_block(&tip_hash);
+    let _tip = scanner.read_block(&tip_hash).unwrap();
 
     // Flip here to write to std::out in TSV format instead.
     let use_db = true;
@@ -39,9 +40,9 @@ pub fn main() {
     let mut current_hash = tip_hash;
 
     loop {
-        let record = scanner.block_index_record(&current_hash);
-        let block = scanner.read_block_from_record(&record);
-        let undo = scanner.read_undo(&current_hash);
+        let record = scanner.block_index_record(&current_hash).unwrap();
+        let block = scanner.read_block_from_record(&record).unwrap();
+        let undo = scanner.read_undo(&current_hash).unwrap();
 
         // Keeping the current input count on the block we are parsing in order
         // to look up the corresponding spent UTXO from the rev file to get the
@@ -0,0 +1,36 @@
+#[path = "util.rs"]
+mod util;
+
+use bitcoin::Amount;
+use bitcoin_scanner::Scanner;
+
+// Walks the whole chain and reconciles the issued supply against the
+// chainstate UTXO set.
+//
+// Usage: cargo run --release --example supply_audit
+pub fn main() {
+    let network = bitcoin::Network::Bitcoin;
+    let data_dir = util::bitcoin_data_dir(network);
+    let mut scanner = Scanner::new(data_dir, network).unwrap();
+
+    let audit = scanner.audit_supply().unwrap();
+    let sat = |amount: u64| Amount::from_sat(amount).to_string();
+
+    println!("Audited up to height {}", audit.height);
+    println!("Subsidy:          {}", sat(audit.subsidy));
+    println!("Coinbase outputs: {}", sat(audit.coinbase_outputs));
+    println!("Fees:             {}", sat(audit.fees));
+    println!("Unclaimed:        {}", sat(audit.unclaimed));
+    println!("Unspendable:      {}", sat(audit.unspendable));
+    println!("Expected UTXOs:   {}", sat(audit.expected_utxo_total()));
+    println!("Chainstate UTXOs: {}", sat(audit.utxo_total.unwrap()));
+    for hash in &audit.overpaying_blocks {
+        println!("Block {} claims more than subsidy and fees", hash);
+    }
+
+    if audit.is_balanced() {
+        println!("Supply is balanced.");
+    } else {
+        println!("Supply does NOT balance!");
+    }
+}
@@ -0,0 +1,71 @@
+#[path = "util.rs"]
+mod util;
+
+use bitcoin::hash_types::FilterHeader;
+use bitcoin::hashes::Hash;
+use bitcoin_scanner::{compute_basic_filter, BlockUndo, Scanner, ScannerError, TxUndo};
+
+// Computes the BIP158 basic filter of every block from block and undo data and
+// cross-checks it against the node's -blockfilterindex where available.
+//
+// Usage: cargo run --example verify_filters [end height]
+pub fn main() {
+    let network = bitcoin::Network::Bitcoin;
+    let data_dir = util::bitcoin_data_dir(network);
+    let end = std::env::args()
+        .nth(1)
+        .map(|arg| arg.parse::<u32>().unwrap());
+
+    let mut scanner = Scanner::new(data_dir, network).unwrap();
+    let tip_height = scanner.active_chain().unwrap().tip_height();
+    let end = end.unwrap_or(tip_height).min(tip_height);
+
+    let mut prev_header = FilterHeader::all_zeros();
+    let mut index_available = true;
+    let mut mismatches = 0;
+
+    // read block by block, the filter index lookups need the scanner too
+    for height in 0..=end {
+        let hash = scanner.hash_at_height(height).unwrap().unwrap();
+        let (record, block, undo) = scanner.read_block_with_undo(&hash).unwrap();
+        // genesis has no undo data, its coinbase spends nothing
+        let undo = undo.unwrap_or(BlockUndo {
+            inner: vec![TxUndo::default()],
+            dsha: [0; 32],
+        });
+
+        let filter = compute_basic_filter(&block, &undo).unwrap();
+        let header = filter.filter_header(&prev_header);
+        prev_header = header;
+
+        if !index_available {
+            println!("{}\t{}", record.height, header);
+            continue;
+        }
+
+        match scanner.block_filter(&hash) {
+            Ok(Some(indexed)) => {
+                if indexed.filter != filter || indexed.header != header {
+                    mismatches += 1;
+                    println!(
+                        "Mismatch at height {}: computed {}, indexed {}",
+                        record.height, header, indexed.header
+                    );
+                }
+            }
+            Ok(None) => println!("Height {} not indexed yet: {}", record.height, header),
+            Err(ScannerError::IndexUnavailable(path)) => {
+                println!("No filter index at {}, printing headers", path.display());
+                println!("{}\t{}", record.height, header);
+                index_available = false;
+            }
+            Err(e) => panic!("{}", e),
+        }
+
+        if record.height % 10_000 == 0 {
+            println!("Checked up to height {}", record.height);
+        }
+    }
+
+    println!("Done, {} mismatches.", mismatches);
+}
@@ -0,0 +1,282 @@
+use std::str::FromStr;
+
+use bitcoin::{blockdata::constants::SUBSIDY_HALVING_INTERVAL, Block, BlockHash, Network};
+
+use crate::{BlockUndo, Scanner, ScannerError};
+
+const COIN: u64 = 100_000_000;
+/// Outputs with a longer script can never be spent and are not added to the UTXO set.
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// The two coinbases that were later overwritten by a duplicate transaction
+/// before BIP30, see `IsBIP30Unspendable` in Bitcoin Core.
+const BIP30_UNSPENDABLE: [(u32, &str); 2] = [
+    (
+        91722,
+        "00000000000271a2dc26e7667f8419f2e15416dc6955e5a6c6cdf3f2574dd08e",
+    ),
+    (
+        91812,
+        "00000000000af0aed4792b1acee3d966af36cf5def14935db8de83d6f9306f2f",
+    ),
+];
+
+/// Blocks between halvings, regtest halves every 150 blocks.
+fn subsidy_halving_interval(network: Network) -> u32 {
+    match network {
+        Network::Regtest => 150,
+        _ => SUBSIDY_HALVING_INTERVAL,
+    }
+}
+
+/// The block subsidy at `height` on `network` in satoshis.
+pub fn block_subsidy(height: u32, network: Network) -> u64 {
+    let halvings = height / subsidy_halving_interval(network);
+    if halvings >= 64 {
+        return 0;
+    }
+    (50 * COIN) >> halvings
+}
+
+/// Running totals of a walk over the chain, all amounts in satoshis.
+///
+/// Every satoshi ever issued is either claimed by a coinbase or left
+/// unclaimed, and every claimed one either sits in the UTXO set or was sent
+/// to an output that can never be spent. At the chainstate tip,
+/// `subsidy - unclaimed - unspendable` must therefore equal the UTXO total.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupplyAudit {
+    network: Network,
+    /// Height of the last block added.
+    pub height: u32,
+    /// Sum of the subsidy schedule over all blocks.
+    pub subsidy: u64,
+    /// Sum of all coinbase outputs, including fees.
+    pub coinbase_outputs: u64,
+    /// Sum of all fees, from the prevout amounts in the undo data.
+    pub fees: u64,
+    /// Subsidy and fees that miners didn't claim.
+    pub unclaimed: u64,
+    /// Outputs that never made it into the UTXO set: the genesis coinbase,
+    /// OP_RETURN and oversized scripts and the BIP30 overwritten coinbases.
+    pub unspendable: u64,
+    /// Blocks whose coinbase claims more than subsidy plus fees.
+    pub overpaying_blocks: Vec<BlockHash>,
+    /// UTXO total of the chainstate, set by [`Scanner::audit_supply`].
+    pub utxo_total: Option<u64>,
+}
+
+impl SupplyAudit {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            height: 0,
+            subsidy: 0,
+            coinbase_outputs: 0,
+            fees: 0,
+            unclaimed: 0,
+            unspendable: 0,
+            overpaying_blocks: vec![],
+            utxo_total: None,
+        }
+    }
+
+    /// Adds the next block, `undo` may only be `None` for genesis.
+    pub fn add_block(
+        &mut self,
+        height: u32,
+        block: &Block,
+        undo: Option<&BlockUndo>,
+    ) -> Result<(), ScannerError> {
+        let hash = block.block_hash();
+        let fees = match undo {
+            Some(undo) => block_fees(block, undo)?,
+            None if height == 0 => 0,
+            None => return Err(ScannerError::MissingUndoData(hash)),
+        };
+
+        let subsidy = block_subsidy(height, self.network);
+        let coinbase = block
+            .txdata
+            .first()
+            .ok_or_else(|| ScannerError::CorruptRecord(format!("block {} is empty", hash)))?;
+        let claimed: u64 = coinbase.output.iter().map(|txout| txout.value).sum();
+
+        self.height = height;
+        self.subsidy += subsidy;
+        self.fees += fees;
+        self.coinbase_outputs += claimed;
+        match (subsidy + fees).checked_sub(claimed) {
+            Some(unclaimed) => self.unclaimed += unclaimed,
+            None => self.overpaying_blocks.push(hash),
+        }
+
+        let coinbase_unspendable = height == 0
+            || self.network == Network::Bitcoin
+                && BIP30_UNSPENDABLE
+                    .iter()
+                    .any(|(h, hex)| *h == height && BlockHash::from_str(hex).unwrap() == hash);
+        for (i, tx) in block.txdata.iter().enumerate() {
+            if i == 0 && coinbase_unspendable {
+                self.unspendable += claimed;
+                continue;
+            }
+            self.unspendable += tx
+                .output
+                .iter()
+                .filter(|txout| {
+                    txout.script_pubkey.is_op_return()
+                        || txout.script_pubkey.len() > MAX_SCRIPT_SIZE
+                })
+                .map(|txout| txout.value)
+                .sum::<u64>();
+        }
+        Ok(())
+    }
+
+    /// The UTXO total the totals so far predict.
+    pub fn expected_utxo_total(&self) -> u64 {
+        self.subsidy - self.unclaimed - self.unspendable
+    }
+
+    /// Whether the chainstate matches the prediction and no block overpays.
+    pub fn is_balanced(&self) -> bool {
+        self.overpaying_blocks.is_empty() && self.utxo_total == Some(self.expected_utxo_total())
+    }
+}
+
+/// Fees of all transactions of a block, the inputs' amounts taken from `undo`.
+fn block_fees(block: &Block, undo: &BlockUndo) -> Result<u64, ScannerError> {
+    let hash = block.block_hash();
+    let mut fees = 0;
+    for (tx, tx_undo) in block.txdata.iter().zip(&undo.inner).skip(1) {
+        if tx.input.len() != tx_undo.0.len() {
+            return Err(ScannerError::CorruptRecord(format!(
+                "undo of {} has {} prevouts for {} inputs of {}",
+                hash,
+                tx_undo.0.len(),
+                tx.input.len(),
+                tx.txid()
+            )));
+        }
+        let spent: u64 = tx_undo.0.iter().map(|prevout| prevout.amount).sum();
+        let created: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        fees += spent.checked_sub(created).ok_or_else(|| {
+            ScannerError::CorruptRecord(format!(
+                "{} in {} spends more than it has",
+                tx.txid(),
+                hash
+            ))
+        })?;
+    }
+    Ok(fees)
+}
+
+impl Scanner {
+    /// Walks the active chain from genesis to the chainstate tip and
+    /// reconciles the issued supply against the UTXO set.
+    ///
+    /// This reads every block and its undo data, expect it to take hours on
+    /// mainnet.
+    pub fn audit_supply(&mut self) -> Result<SupplyAudit, ScannerError> {
+        let tip = self.tip_hash;
+        let tip_height = self
+            .height_of(&tip)?
+            .ok_or(ScannerError::UnknownBlock(tip))?;
+
+        let mut audit = SupplyAudit::new(self.network());
+        for block in self.blocks(..=tip_height)? {
+            let (record, block, undo) = block?;
+            audit.add_block(record.height, &block, undo.as_ref())?;
+        }
+
+        let mut utxo_total = 0;
+        for coin in self.utxos()? {
+            utxo_total += coin?.amount;
+        }
+        audit.utxo_total = Some(utxo_total);
+        Ok(audit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime, blockdata::constants::genesis_block, OutPoint, ScriptBuf, Sequence,
+        Transaction, TxIn, TxOut, Witness,
+    };
+
+    use super::*;
+    use crate::{TxInUndo, TxUndo};
+
+    fn tx(input: OutPoint, values: &[u64]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: input,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: ScriptBuf::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn fees_come_from_the_undo_of_each_transaction() {
+        let genesis = genesis_block(Network::Regtest);
+        let spend = tx(
+            OutPoint::new(genesis.txdata[0].txid(), 0),
+            &[7 * COIN, 2 * COIN],
+        );
+        let mut op_return = tx(OutPoint::new(spend.txid(), 0), &[]);
+        op_return.output.push(TxOut {
+            value: COIN,
+            script_pubkey: ScriptBuf::new_op_return(&[]),
+        });
+        let coinbase = tx(OutPoint::null(), &[50 * COIN, 6 * COIN]);
+
+        let mut block = genesis.clone();
+        block.header.prev_blockhash = genesis.block_hash();
+        block.txdata = vec![coinbase, spend, op_return];
+
+        let prevout = |amount| TxInUndo {
+            coinbase: 0,
+            height: 1,
+            script: ScriptBuf::new(),
+            amount,
+        };
+        let undo = BlockUndo {
+            inner: vec![
+                TxUndo::default(),
+                TxUndo(vec![prevout(10 * COIN)]),
+                TxUndo(vec![prevout(7 * COIN)]),
+            ],
+            dsha: [0; 32],
+        };
+
+        assert_eq!(block_fees(&block, &undo).unwrap(), 7 * COIN);
+
+        let mut audit = SupplyAudit::new(Network::Regtest);
+        audit.add_block(0, &genesis, None).unwrap();
+        audit.add_block(1, &block, Some(&undo)).unwrap();
+        assert_eq!(audit.subsidy, 100 * COIN);
+        assert_eq!(audit.fees, 7 * COIN);
+        assert_eq!(audit.coinbase_outputs, 106 * COIN);
+        assert_eq!(audit.unclaimed, COIN);
+        assert_eq!(audit.unspendable, 51 * COIN);
+        assert!(audit.overpaying_blocks.is_empty());
+
+        // a prevout missing from the undo data is caught
+        let mut short = undo.clone();
+        short.inner[2].0.clear();
+        assert!(block_fees(&block, &short).is_err());
+    }
+}
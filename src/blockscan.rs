@@ -1,86 +1,371 @@
-// unused for now
-
 use std::{
     collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
     path::{Path, PathBuf},
-    sync::Arc,
 };
 
-use futures::StreamExt;
-use pycoin::serialize::{parse_compact, parse_varint};
-use pycoin::{
-    block::{Block, BlockHeader},
-    undo::{UndoBlock, UndoCoin},
+use bitcoin::{
+    blockdata::block::Header,
+    consensus::{deserialize, Decodable},
+    Block, BlockHash, Network,
 };
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncSeekExt, BufReader},
-    sync::Mutex,
+
+use crate::{
+    read_compact_size, testnet4::chains, undo_checksum, ActiveChain, BlockFileStore, BlockUndo,
+    Scanner, ScannerError, XorKey, XorReader, MAX_SIZE,
 };
 
-pub struct BlockscanRecord {
-    pub block_id: String,
-    pub block_height: Option<u32>,
-    pub blk_name: String,
-    pub blk_start: u64,
-    pub blk_size: u32,
-    pub rev_name: String,
-    pub rev_start: u64,
-    pub rev_size: u32,
-    pub hblock: Option<BlockHeader>,
+/// A block found in the blk files by [`RawBlockScanner`].
+#[derive(Debug, Clone)]
+pub struct RawBlockRecord {
+    pub header: Header,
+    pub num_transactions: u32,
+    /// Number of the blk file, undo data is in the rev file of the same number.
+    pub file: u32,
+    /// Offset of the block in the blk file, after its magic and size.
+    pub block_offset: u64,
+    /// Offset of the undo data in the rev file, if a matching record was found.
+    pub undo_offset: Option<u64>,
 }
 
-pub struct Quickscanner {
-    pub blocks_path: PathBuf,
-    pub brd: HashMap<String, BlockscanRecord>,
-    pub cache: Arc<Mutex<HashMap<String, ProcessedBlock>>>,
+/// Finds blocks by reading the blk/rev files directly, without the block
+/// index LevelDB.
+///
+/// Every blk file is walked record by record using the magic and size
+/// framing. Undo records are paired with the blocks of the blk file with
+/// the same number by transaction count and their checksum, which commits
+/// to the previous block hash. The chain with the most work starting at
+/// genesis is the active chain.
+pub struct RawBlockScanner {
+    block_files: BlockFileStore,
+    records: HashMap<BlockHash, RawBlockRecord>,
+    chain: ActiveChain,
 }
 
-impl Quickscanner {
-    pub async fn new(blocks_path: Option<PathBuf>) -> Quickscanner {
-        let blocks_path = match blocks_path {
-            Some(path) => path,
-            None => {
-                let default_path = dirs::home_dir()
-                    .expect("Failed to get home dir")
-                    .join(".bitcoin/blocks");
-                default_path
+impl RawBlockScanner {
+    /// Scans the blk files in `blocks_dir` and, if `with_undo` is set, the rev
+    /// files. Reading the rev files takes about as long as the blk files.
+    pub fn scan(
+        blocks_dir: PathBuf,
+        network: Network,
+        with_undo: bool,
+    ) -> Result<Self, ScannerError> {
+        let xor_key = XorKey::read(&blocks_dir)?;
+        let (magic, genesis) = detect_chain(&blocks_dir, network, xor_key)?;
+
+        // only used to read blocks when pairing is ambiguous
+        let block_files = BlockFileStore::new(blocks_dir.clone(), magic, xor_key);
+        let mut records = HashMap::new();
+        for file in block_file_numbers(&blocks_dir)? {
+            let path = blocks_dir.join(format!("blk{:05}.dat", file));
+            for (hash, record) in scan_blk_file(&path, file, magic, xor_key)? {
+                // after a crash the same block can be stored twice
+                records.entry(hash).or_insert(record);
             }
-        };
 
-        Quickscanner {
-            blocks_path,
-            brd: HashMap::new(),
-            cache: Arc::new(Mutex::new(HashMap::new())),
+            if with_undo {
+                let path = blocks_dir.join(format!("rev{:05}.dat", file));
+                if path.exists() {
+                    pair_undo(&path, file, magic, xor_key, &block_files, &mut records)?;
+                }
+            }
         }
+
+        let chain = most_work_chain(&records, genesis.block_hash())?;
+
+        Ok(Self {
+            block_files,
+            records,
+            chain,
+        })
+    }
+
+    /// The most-work chain of the blocks found, starting at genesis.
+    pub fn active_chain(&self) -> &ActiveChain {
+        &self.chain
+    }
+
+    pub fn record(&self, hash: &BlockHash) -> Option<&RawBlockRecord> {
+        self.records.get(hash)
+    }
+
+    /// All blocks found, including stale ones and blocks not connected to genesis.
+    pub fn records(&self) -> impl Iterator<Item = (&BlockHash, &RawBlockRecord)> {
+        self.records.iter()
+    }
+
+    pub fn read_block(&self, hash: &BlockHash) -> Result<Block, ScannerError> {
+        let record = self.record(hash).ok_or(ScannerError::UnknownBlock(*hash))?;
+        read_block(&self.block_files, record)
     }
 
-    pub async fn scanfs(&mut self, nlatest: usize, ignore_rev: bool) -> usize {
-        let latest_block_files = get_latest_files(&self.blocks_path, "blk*.dat", nlatest).await;
-        let latest_undo_files = get_latest_files(&self.blocks_path, "rev*.dat", nlatest).await;
+    pub fn read_undo(&self, hash: &BlockHash) -> Result<BlockUndo, ScannerError> {
+        let record = self.record(hash).ok_or(ScannerError::UnknownBlock(*hash))?;
+        let offset = record
+            .undo_offset
+            .ok_or(ScannerError::MissingUndoData(*hash))?;
+
+        let buf = self.block_files.undo_record(record.file, offset)?;
+        let undo = BlockUndo::parse(&mut &buf[..], Some(record.num_transactions))
+            .map_err(|e| ScannerError::Decode(e.into()))?;
+        Ok(undo)
+    }
 
-        let mut total_results = 0;
+    /// Reads the blocks of the active chain in ascending height order, with
+    /// their undo data if it was paired.
+    pub fn blocks(
+        &self,
+    ) -> impl Iterator<Item = Result<(u32, Block, Option<BlockUndo>), ScannerError>> + '_ {
+        self.chain
+            .hashes()
+            .iter()
+            .enumerate()
+            .map(|(height, hash)| {
+                let block = self.read_block(hash)?;
+                let undo = match self.read_undo(hash) {
+                    Ok(undo) => Some(undo),
+                    Err(ScannerError::MissingUndoData(_)) => None,
+                    Err(e) => return Err(e),
+                };
+                Ok((height as u32, block, undo))
+            })
+    }
+}
 
-        for (path_blk, path_rev) in latest_block_files
-            .into_iter()
-            .zip(latest_undo_files.into_iter())
+/// The magic and genesis block of the chain `network` stands for, told
+/// apart by the magic blk00000.dat starts with if there are several.
+fn detect_chain(
+    blocks_dir: &Path,
+    network: Network,
+    xor_key: XorKey,
+) -> Result<([u8; 4], Block), ScannerError> {
+    let mut chains = chains(network);
+    if chains.len() > 1 {
+        let path = blocks_dir.join("blk00000.dat");
+        let mut magic = [0; 4];
+        if Scanner::open_file(&path)
+            .and_then(|file| Ok(XorReader::new(file, xor_key).read_exact(&mut magic)?))
+            .is_ok()
         {
-            let results = if ignore_rev {
-                quickscan(&path_blk, None).await
-            } else {
-                quickscan(&path_blk, Some(&path_rev)).await
-            };
+            if let Some(i) = chains.iter().position(|(m, _)| *m == magic) {
+                return Ok(chains.swap_remove(i));
+            }
+        }
+    }
+    Ok(chains.swap_remove(0))
+}
 
-            total_results += results.len();
-            for r in results {
-                self.brd.insert(r.block_id.clone(), r);
+/// Numbers of all blk files in `blocks_dir`, ascending.
+fn block_file_numbers(blocks_dir: &Path) -> Result<Vec<u32>, ScannerError> {
+    let mut numbers = vec![];
+    for entry in fs::read_dir(blocks_dir)? {
+        let name = entry?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("blk"))
+            .and_then(|name| name.strip_suffix(".dat"))
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Reads the header and transaction count of every block in a blk file.
+///
+/// Bitcoin Core preallocates blk files, the first record without the
+/// network magic marks the end of the data.
+fn scan_blk_file(
+    path: &Path,
+    file: u32,
+    magic: [u8; 4],
+    xor_key: XorKey,
+) -> Result<Vec<(BlockHash, RawBlockRecord)>, ScannerError> {
+    let len = fs::metadata(path)?.len();
+    let mut reader = XorReader::new(Scanner::open_file(path)?, xor_key);
+
+    let mut records = vec![];
+    let mut pos = 0;
+    while pos + 8 <= len {
+        let mut magic_size = [0; 8];
+        reader.read_exact(&mut magic_size)?;
+        let size = u32::from_le_bytes(magic_size[4..8].try_into().unwrap()) as u64;
+        if magic_size[..4] != magic || !(81..=MAX_SIZE).contains(&size) || pos + 8 + size > len {
+            break;
+        }
+
+        let header = Header::consensus_decode(&mut reader)?;
+        let num_transactions = match read_compact_size(&mut reader) {
+            Ok(n) => n,
+            // a damaged record, its framing still says where the next one starts
+            Err(_) => {
+                pos += 8 + size;
+                reader.seek(SeekFrom::Start(pos))?;
+                continue;
             }
+        };
+        let read = 80 + compact_size_len(num_transactions);
+        if read > size {
+            break;
         }
+        reader.seek_relative((size - read) as i64)?;
 
-        total_results
+        // skip anything that isn't a block, like a partially overwritten record
+        if header.validate_pow(header.target()).is_ok() {
+            let record = RawBlockRecord {
+                header,
+                num_transactions: num_transactions as u32,
+                file,
+                block_offset: pos + 8,
+                undo_offset: None,
+            };
+            records.push((header.block_hash(), record));
+        }
+        pos += 8 + size;
+    }
+    Ok(records)
+}
+
+fn compact_size_len(n: u64) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+/// Matches the undo records of a rev file with the blocks of its blk file.
+fn pair_undo(
+    path: &Path,
+    file: u32,
+    magic: [u8; 4],
+    xor_key: XorKey,
+    block_files: &BlockFileStore,
+    records: &mut HashMap<BlockHash, RawBlockRecord>,
+) -> Result<(), ScannerError> {
+    let mut by_tx_count: HashMap<u32, Vec<BlockHash>> = HashMap::new();
+    for (hash, record) in records.iter() {
+        if record.file == file {
+            by_tx_count
+                .entry(record.num_transactions)
+                .or_default()
+                .push(*hash);
+        }
+    }
+
+    let len = fs::metadata(path)?.len();
+    let mut reader = XorReader::new(Scanner::open_file(path)?, xor_key);
+
+    let mut pos = 0;
+    while pos + 8 <= len {
+        let mut magic_size = [0; 8];
+        reader.read_exact(&mut magic_size)?;
+        let size = u32::from_le_bytes(magic_size[4..8].try_into().unwrap()) as u64;
+        // the checksum follows the record without being counted in its size
+        if magic_size[..4] != magic || size > MAX_SIZE || pos + 8 + size + 32 > len {
+            break;
+        }
+        let mut buf = vec![0; size as usize + 32];
+        reader.read_exact(&mut buf)?;
+        let (body, checksum) = buf.split_at(size as usize);
+        let offset = pos + 8;
+        pos += 8 + size + 32;
+
+        let num_transactions = match read_compact_size(&mut &body[..]) {
+            Ok(n) => n as u32 + 1,
+            Err(_) => continue,
+        };
+        let candidates = match by_tx_count.get(&num_transactions) {
+            Some(candidates) => candidates,
+            None => continue,
+        };
+        let mut matches: Vec<BlockHash> = candidates
+            .iter()
+            .filter(|hash| {
+                let prev = records[*hash].header.prev_blockhash;
+                undo_checksum(&prev, body)[..] == checksum[..]
+            })
+            .copied()
+            .collect();
+
+        // siblings share the previous block, compare the spent prevouts.
+        // Undo data or blocks that fail to decode fit nothing.
+        if matches.len() > 1 {
+            let undo = match BlockUndo::parse(&mut &buf[..], Some(num_transactions)) {
+                Ok(undo) => undo,
+                Err(_) => continue,
+            };
+            matches.retain(|hash| match read_block(block_files, &records[hash]) {
+                Ok(block) => block
+                    .txdata
+                    .iter()
+                    .zip(&undo.inner)
+                    .skip(1)
+                    .all(|(tx, tx_undo)| tx.input.len() == tx_undo.0.len()),
+                Err(_) => false,
+            });
+        }
+
+        // leave identical-looking candidates unpaired rather than guessing
+        if let [hash] = matches[..] {
+            records.get_mut(&hash).unwrap().undo_offset = Some(offset);
+        }
     }
+    Ok(())
+}
 
-    // ... Rest of the code
+fn read_block(
+    block_files: &BlockFileStore,
+    record: &RawBlockRecord,
+) -> Result<Block, ScannerError> {
+    let buf = block_files.block_record(record.file, record.block_offset)?;
+    Ok(deserialize(&buf)?)
 }
 
-// Other functions (get_latest_files, quickscan, etc.) translated to async Rust code
+/// Links the blocks by their previous block hash and returns the chain from
+/// `genesis` to the tip with the most work. Ties go to the block found first.
+fn most_work_chain(
+    records: &HashMap<BlockHash, RawBlockRecord>,
+    genesis: BlockHash,
+) -> Result<ActiveChain, ScannerError> {
+    let genesis_record = records
+        .get(&genesis)
+        .ok_or(ScannerError::MissingBlockData(genesis))?;
+
+    let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+    for (hash, record) in records {
+        if *hash != genesis {
+            children
+                .entry(record.header.prev_blockhash)
+                .or_default()
+                .push(*hash);
+        }
+    }
+
+    let position = |hash: &BlockHash| {
+        let record = &records[hash];
+        (record.file, record.block_offset)
+    };
+    let mut best = (genesis_record.header.work(), genesis);
+    let mut stack = vec![best];
+    while let Some((work, hash)) = stack.pop() {
+        if work > best.0 || work == best.0 && position(&hash) < position(&best.1) {
+            best = (work, hash);
+        }
+        for child in children.get(&hash).into_iter().flatten() {
+            stack.push((work + records[child].header.work(), *child));
+        }
+    }
+
+    let mut hashes = vec![best.1];
+    while hashes.last() != Some(&genesis) {
+        let prev = records[hashes.last().unwrap()].header.prev_blockhash;
+        hashes.push(prev);
+    }
+    hashes.reverse();
+    Ok(ActiveChain::new(hashes))
+}
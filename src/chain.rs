@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use bitcoin::BlockHash;
+
+/// The best chain as a height-indexed list of block hashes.
+#[derive(Debug, Clone)]
+pub struct ActiveChain {
+    hashes: Vec<BlockHash>,
+    heights: HashMap<BlockHash, u32>,
+}
+
+impl ActiveChain {
+    /// Builds the chain from hashes ordered by height, starting at genesis.
+    pub(crate) fn new(hashes: Vec<BlockHash>) -> Self {
+        let heights = hashes
+            .iter()
+            .enumerate()
+            .map(|(height, hash)| (*hash, height as u32))
+            .collect();
+        Self { hashes, heights }
+    }
+
+    pub fn hash_at_height(&self, height: u32) -> Option<BlockHash> {
+        self.hashes.get(height as usize).copied()
+    }
+
+    pub fn height_of(&self, hash: &BlockHash) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.heights.contains_key(hash)
+    }
+
+    pub fn tip(&self) -> BlockHash {
+        *self.hashes.last().expect("chain contains at least genesis")
+    }
+
+    pub fn tip_height(&self) -> u32 {
+        self.hashes.len() as u32 - 1
+    }
+
+    pub fn hashes(&self) -> &[BlockHash] {
+        &self.hashes
+    }
+}
+
+/// Status of a chain tip, as reported by `getchaintips`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChainTipStatus {
+    /// The tip of the active chain.
+    Active,
+    /// A fully validated branch that is not part of the active chain.
+    ValidFork,
+    /// All blocks are available but the branch was never fully validated.
+    ValidHeaders,
+    /// Some blocks of the branch were never downloaded.
+    HeadersOnly,
+    /// The branch contains at least one invalid block.
+    Invalid,
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainTip {
+    pub hash: BlockHash,
+    pub height: u32,
+    /// Number of blocks since the fork with the active chain, zero for the active tip.
+    pub branch_len: u32,
+    pub status: ChainTipStatus,
+}
@@ -0,0 +1,109 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use bitcoin::{hashes::Hash, OutPoint, ScriptBuf, Txid};
+use rusty_leveldb::{DBIterator, LdbIterator};
+
+use crate::{
+    read_compressed_txout, read_varint_core, write_compressed_txout, write_varint_core, Scanner,
+    ScannerError,
+};
+
+/// An unspent transaction output from the chainstate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub outpoint: OutPoint,
+    pub height: u32,
+    pub is_coinbase: bool,
+    pub amount: u64,
+    pub script: ScriptBuf,
+}
+
+impl Coin {
+    /// Decodes a `C` record, `value` must already be de-obfuscated.
+    pub(crate) fn parse(key: &[u8], value: &[u8]) -> Result<Self, ScannerError> {
+        let corrupt = |e: Error| ScannerError::CorruptRecord(format!("chainstate coin: {}", e));
+
+        let outpoint = parse_key(key).map_err(corrupt)?;
+
+        let mut r = value;
+        let coin = Self::deserialize(outpoint, &mut r).map_err(corrupt)?;
+        if !r.is_empty() {
+            return Err(corrupt(Error::new(
+                ErrorKind::InvalidData,
+                "trailing bytes",
+            )));
+        }
+        Ok(coin)
+    }
+
+    /// Reads Bitcoin Core's `Coin` serialization: varint `height << 1 | coinbase`
+    /// followed by the compressed output. Used by the chainstate and snapshots.
+    pub fn deserialize<R: Read>(outpoint: OutPoint, reader: &mut R) -> std::io::Result<Self> {
+        let code = read_varint_core(reader)?;
+        let (amount, script) = read_compressed_txout(reader)?;
+
+        Ok(Self {
+            outpoint,
+            height: (code >> 1) as u32,
+            is_coinbase: code & 1 == 1,
+            amount,
+            script,
+        })
+    }
+
+    /// Writes the coin like [`Coin::deserialize`] reads it, without the outpoint.
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write_varint_core(writer, (self.height as u64) << 1 | self.is_coinbase as u64)?;
+        write_compressed_txout(writer, self.amount, &self.script)
+    }
+}
+
+/// Builds the chainstate key of an outpoint: `C`, txid, varint vout.
+pub(crate) fn coin_key(outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = vec![b'C'];
+    key.extend_from_slice(&outpoint.txid.to_byte_array());
+    write_varint_core(&mut key, outpoint.vout as u64).unwrap();
+    key
+}
+
+fn parse_key(key: &[u8]) -> std::io::Result<OutPoint> {
+    if key.len() < 34 || key[0] != b'C' {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid key"));
+    }
+    let txid = Txid::from_slice(&key[1..33]).unwrap();
+    let mut r = &key[33..];
+    let vout = read_varint_core(&mut r)? as u32;
+    Ok(OutPoint { txid, vout })
+}
+
+/// Iterates all coins in the chainstate in key order, see [`Scanner::utxos`].
+pub struct Utxos {
+    it: DBIterator,
+    obfs: Option<Vec<u8>>,
+}
+
+impl Utxos {
+    pub(crate) fn new(mut it: DBIterator, obfs: Option<Vec<u8>>) -> Self {
+        it.seek(b"C");
+        Self { it, obfs }
+    }
+}
+
+impl Iterator for Utxos {
+    type Item = Result<Coin, ScannerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.it.valid() {
+            return None;
+        }
+        let (mut key, mut value) = (vec![], vec![]);
+        self.it.current(&mut key, &mut value);
+        if key.first() != Some(&b'C') {
+            return None;
+        }
+        self.it.advance();
+
+        let value = Scanner::obfs(&self.obfs, &value);
+        Some(Coin::parse(&key, &value))
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+
+use bitcoin::{
+    hashes::{hash_newtype, sha256, sha256d, Hash, HashEngine},
+    BlockHash,
+};
+
+use crate::{index::IndexDb, write_compact_size, Coin, MuHash3072, Scanner, ScannerError};
+
+hash_newtype! {
+    /// A finalized MuHash3072 UTXO set commitment, displayed like
+    /// `gettxoutsetinfo muhash`.
+    #[hash_newtype(backward)]
+    pub struct MuHash(sha256::Hash);
+}
+
+/// UTXO set statistics after a block, from Bitcoin Core's `-coinstatsindex`.
+/// Amounts are in satoshis and accumulate from genesis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinStatsRecord {
+    pub block_hash: BlockHash,
+    pub height: u32,
+    pub muhash: MuHash,
+    pub transaction_output_count: u64,
+    pub bogo_size: u64,
+    pub total_amount: i64,
+    pub total_subsidy: i64,
+    pub total_unspendable_amount: i64,
+    pub total_prevout_spent_amount: i64,
+    pub total_new_outputs_ex_coinbase_amount: i64,
+    pub total_coinbase_amount: i64,
+    pub total_unspendables_genesis_block: i64,
+    pub total_unspendables_bip30: i64,
+    pub total_unspendables_scripts: i64,
+    pub total_unspendables_unclaimed_rewards: i64,
+}
+
+impl CoinStatsRecord {
+    fn parse(block_hash: BlockHash, height: u32, value: &[u8]) -> Result<Self, ScannerError> {
+        if value.len() != 32 + 12 * 8 {
+            return Err(ScannerError::CorruptRecord(format!(
+                "coinstats entry of {} has {} bytes",
+                block_hash,
+                value.len()
+            )));
+        }
+        let muhash = MuHash::from_slice(&value[..32]).unwrap();
+        let mut fields = value[32..]
+            .chunks_exact(8)
+            .map(|field| u64::from_le_bytes(field.try_into().unwrap()));
+        let mut next = || fields.next().unwrap();
+
+        Ok(Self {
+            block_hash,
+            height,
+            muhash,
+            transaction_output_count: next(),
+            bogo_size: next(),
+            total_amount: next() as i64,
+            total_subsidy: next() as i64,
+            total_unspendable_amount: next() as i64,
+            total_prevout_spent_amount: next() as i64,
+            total_new_outputs_ex_coinbase_amount: next() as i64,
+            total_coinbase_amount: next() as i64,
+            total_unspendables_genesis_block: next() as i64,
+            total_unspendables_bip30: next() as i64,
+            total_unspendables_scripts: next() as i64,
+            total_unspendables_unclaimed_rewards: next() as i64,
+        })
+    }
+}
+
+impl Scanner {
+    /// Reads the UTXO set statistics after a block from
+    /// `indexes/coinstats`. `None` if the index has not reached the block yet.
+    pub fn coin_stats(
+        &mut self,
+        hash: &BlockHash,
+    ) -> Result<Option<CoinStatsRecord>, ScannerError> {
+        let height = self.block_index_record(hash)?.height;
+
+        if self.coinstats_index.is_none() {
+            let path = self.datadir.join("indexes").join("coinstats").join("db");
+            self.coinstats_index = Some(IndexDb::open(path, self.copy.as_ref())?);
+        }
+        let coinstats_index = self.coinstats_index.as_mut().unwrap();
+
+        match coinstats_index.get_block_value(height, hash) {
+            Some(value) => CoinStatsRecord::parse(*hash, height, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the UTXO set statistics after the active chain block at `height`.
+    pub fn coin_stats_at_height(
+        &mut self,
+        height: u32,
+    ) -> Result<Option<CoinStatsRecord>, ScannerError> {
+        match self.hash_at_height(height)? {
+            Some(hash) => self.coin_stats(&hash),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Which UTXO set commitment of `gettxoutsetinfo` to compute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UtxoSetHashKind {
+    /// `hash_type=muhash`
+    MuHash,
+    /// `hash_type=hash_serialized_3`
+    HashSerialized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UtxoSetHash {
+    MuHash(MuHash),
+    HashSerialized(sha256d::Hash),
+}
+
+impl fmt::Display for UtxoSetHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UtxoSetHash::MuHash(hash) => fmt::Display::fmt(hash, f),
+            UtxoSetHash::HashSerialized(hash) => fmt::Display::fmt(hash, f),
+        }
+    }
+}
+
+/// Incrementally computes a UTXO set commitment. Both commit to every coin
+/// serialized as outpoint, `height << 1 | coinbase` and the output; the
+/// serialized hash also commits to the order coins are added in.
+pub enum UtxoSetHasher {
+    MuHash(Box<MuHash3072>),
+    HashSerialized(sha256::HashEngine),
+}
+
+impl UtxoSetHasher {
+    pub fn new(kind: UtxoSetHashKind) -> Self {
+        match kind {
+            UtxoSetHashKind::MuHash => UtxoSetHasher::MuHash(Box::default()),
+            UtxoSetHashKind::HashSerialized => {
+                UtxoSetHasher::HashSerialized(sha256d::Hash::engine())
+            }
+        }
+    }
+
+    pub fn add(&mut self, coin: &Coin) {
+        let mut data = Vec::with_capacity(36 + 4 + 8 + 1 + coin.script.len());
+        data.extend_from_slice(&coin.outpoint.txid.to_byte_array());
+        data.extend_from_slice(&coin.outpoint.vout.to_le_bytes());
+        data.extend_from_slice(&(coin.height << 1 | coin.is_coinbase as u32).to_le_bytes());
+        data.extend_from_slice(&coin.amount.to_le_bytes());
+        write_compact_size(&mut data, coin.script.len() as u64).unwrap();
+        data.extend_from_slice(coin.script.as_bytes());
+
+        match self {
+            UtxoSetHasher::MuHash(muhash) => muhash.insert(&data),
+            UtxoSetHasher::HashSerialized(engine) => engine.input(&data),
+        }
+    }
+
+    pub fn finish(self) -> UtxoSetHash {
+        match self {
+            UtxoSetHasher::MuHash(muhash) => UtxoSetHash::MuHash(muhash.finalize()),
+            UtxoSetHasher::HashSerialized(engine) => {
+                UtxoSetHash::HashSerialized(sha256d::Hash::from_engine(engine))
+            }
+        }
+    }
+}
+
+/// A UTXO set commitment with the totals `gettxoutsetinfo` reports with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoSetStats {
+    /// The chainstate tip the set belongs to.
+    pub block_hash: BlockHash,
+    pub hash: UtxoSetHash,
+    pub txouts: u64,
+    pub total_amount: u64,
+}
+
+impl Scanner {
+    /// Computes a `gettxoutsetinfo` commitment over the whole chainstate.
+    pub fn utxo_set_hash(&mut self, kind: UtxoSetHashKind) -> Result<UtxoSetStats, ScannerError> {
+        let mut hasher = UtxoSetHasher::new(kind);
+        let mut txouts = 0;
+        let mut total_amount = 0;
+        for coin in self.utxos()? {
+            let coin = coin?;
+            hasher.add(&coin);
+            txouts += 1;
+            total_amount += coin.amount;
+        }
+
+        Ok(UtxoSetStats {
+            block_hash: self.tip_hash,
+            hash: hasher.finish(),
+            txouts,
+            total_amount,
+        })
+    }
+}
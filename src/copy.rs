@@ -0,0 +1,212 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusty_leveldb::DB;
+
+use crate::{Scanner, ScannerError};
+
+/// How often to restart copying a database that bitcoind compacted under us.
+const COPY_ATTEMPTS: usize = 10;
+
+/// A temporary directory holding copies of a running node's LevelDBs,
+/// removed again on drop.
+pub(crate) struct CopyDir {
+    root: PathBuf,
+    datadir: PathBuf,
+}
+
+impl CopyDir {
+    /// Creates a fresh directory inside `parent`, which must be on the same
+    /// file system as the datadir's databases so their tables can be hard-linked.
+    /// Directories left in `parent` by scanners that died are removed first.
+    pub(crate) fn new(datadir: &Path, parent: &Path) -> Result<Self, ScannerError> {
+        remove_stale(parent)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let root = parent.join(format!("bitcoin-scanner-{}-{}", std::process::id(), nanos));
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            datadir: datadir.to_owned(),
+        })
+    }
+
+    /// Copies the LevelDB at `path` inside the datadir and returns the path
+    /// of the copy, which can be opened without taking bitcoind's lock.
+    ///
+    /// Table files never change once written, so they are hard-linked, a
+    /// full copy of the chainstate would take gigabytes. CURRENT, the
+    /// manifest and the logs are copied. Every compaction or log switch
+    /// appends to the manifest, if it grew while we copied, a file we need
+    /// may be gone and we start over.
+    pub(crate) fn copy_db(&self, path: &Path) -> Result<PathBuf, ScannerError> {
+        for _ in 0..COPY_ATTEMPTS {
+            if let Some(dest) = self.try_copy_db(path)? {
+                return Ok(dest);
+            }
+        }
+        Err(kept_changing(path))
+    }
+
+    /// Copies the chainstate like [`CopyDir::copy_db`] and opens the copy.
+    ///
+    /// bitcoind flushes the UTXO set in several batches: the first erases
+    /// the best block `B` and writes the head blocks `H`, the last writes
+    /// `B` back and erases `H`. A copy taken in between holds a half-applied
+    /// UTXO set, it is taken again.
+    pub(crate) fn copy_chain_state(
+        &self,
+        path: &Path,
+    ) -> Result<(DB, Option<Vec<u8>>), ScannerError> {
+        for _ in 0..COPY_ATTEMPTS {
+            let Some(dest) = self.try_copy_db(path)? else {
+                continue;
+            };
+            let (mut db, obfs) = Scanner::open_db(&dest)?;
+            if db.get(b"B").is_some() && db.get(b"H").is_none() {
+                return Ok((db, obfs));
+            }
+        }
+        Err(kept_changing(path))
+    }
+
+    /// Returns the path of the copy, or `None` if it is inconsistent.
+    fn try_copy_db(&self, path: &Path) -> Result<Option<PathBuf>, ScannerError> {
+        let relative = path.strip_prefix(&self.datadir).unwrap_or(path);
+        let dest = self.root.join(relative);
+
+        if dest.exists() {
+            fs::remove_dir_all(&dest)?;
+        }
+        fs::create_dir_all(&dest)?;
+        match copy_db_files(path, &dest) {
+            Ok(true) => Ok(Some(dest)),
+            Ok(false) => Ok(None),
+            Err(ScannerError::Io(e)) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Removes the copy directories of processes that are no longer running.
+/// Their hard links would keep every table bitcoind compacts away.
+fn remove_stale(parent: &Path) -> Result<(), ScannerError> {
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let pid = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("bitcoin-scanner-"))
+            .and_then(|rest| rest.split('-').next())
+            .and_then(|pid| pid.parse::<u32>().ok());
+        match pid {
+            Some(pid) if !is_running(pid) => match fs::remove_dir_all(entry.path()) {
+                Ok(()) => {}
+                // another scanner cleaned up first
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether a process with this pid exists. Only known on Linux, elsewhere
+/// every process is assumed to be running and nothing is removed.
+fn is_running(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        pid == std::process::id() || Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
+
+fn kept_changing(path: &Path) -> ScannerError {
+    ScannerError::CorruptRecord(format!("{} kept changing while copying it", path.display()))
+}
+
+impl Drop for CopyDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Returns whether the copy is consistent.
+fn copy_db_files(src: &Path, dest: &Path) -> Result<bool, ScannerError> {
+    let current = fs::read_to_string(src.join("CURRENT"))?;
+    let manifest = current.trim_end();
+    fs::write(dest.join("CURRENT"), &current)?;
+    let manifest_len = fs::copy(src.join(manifest), dest.join(manifest))?;
+
+    let mut logs = vec![];
+    let mut tables = vec![];
+    for entry in fs::read_dir(src)? {
+        let name = entry?.file_name();
+        match Path::new(&name).extension().and_then(|ext| ext.to_str()) {
+            Some("log") => logs.push(name),
+            Some("ldb") | Some("sst") => tables.push(name),
+            _ => {}
+        }
+    }
+
+    for name in logs {
+        fs::copy(src.join(&name), dest.join(&name))?;
+    }
+    for name in tables {
+        let (from, to) = (src.join(&name), dest.join(&name));
+        match fs::hard_link(&from, &to) {
+            Ok(()) => {}
+            // compacted away meanwhile
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(e.into()),
+            // usually another file system
+            Err(e) => return Err(ScannerError::SnapshotLink(from, e)),
+        }
+    }
+
+    Ok(fs::read_to_string(src.join("CURRENT"))? == current
+        && fs::metadata(src.join(manifest))?.len() == manifest_len)
+}
+
+/// What changes whenever bitcoind writes to a LevelDB: CURRENT and the
+/// names and lengths of the manifest and logs, which are only appended to.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DbState {
+    current: String,
+    files: Vec<(OsString, u64)>,
+}
+
+impl DbState {
+    pub(crate) fn read(path: &Path) -> io::Result<Self> {
+        let current = fs::read_to_string(path.join("CURRENT"))?;
+        let mut files = vec![];
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let is_log = Path::new(&name).extension() == Some(OsStr::new("log"));
+            if !is_log && !name.to_string_lossy().starts_with("MANIFEST-") {
+                continue;
+            }
+            match entry.metadata() {
+                Ok(metadata) => files.push((name, metadata.len())),
+                // removed meanwhile, its successor shows up in the listing
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        files.sort();
+        Ok(Self { current, files })
+    }
+}
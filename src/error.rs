@@ -0,0 +1,136 @@
+use std::{fmt, io, path::PathBuf};
+
+use bitcoin::{consensus::encode, BlockHash, Network};
+
+/// Everything that can go wrong while reading a Bitcoin Core datadir.
+#[derive(Debug)]
+pub enum ScannerError {
+    /// The LevelDB at this path is locked, usually because bitcoind is still running.
+    DatabaseLocked(PathBuf),
+    /// The LevelDB at this path could not be opened or read.
+    Database(PathBuf, rusty_leveldb::Status),
+    /// The optional index at this path does not exist, the node was not
+    /// started with the matching `-txindex`/`-blockfilterindex`/... option.
+    IndexUnavailable(PathBuf),
+    /// The datadir belongs to another chain than the one asked for. `found`
+    /// is the chain whose magic the block files use, if it is a known one.
+    WrongNetwork {
+        expected: Network,
+        found: Option<Network>,
+    },
+    /// A table file could not be hard-linked into the copy directory of a
+    /// snapshot, usually because the directory is on another file system.
+    SnapshotLink(PathBuf, io::Error),
+    /// The block is not known to the block index.
+    UnknownBlock(BlockHash),
+    /// The block is above the chainstate tip of a [`Scanner::new_snapshot`]
+    /// scanner, its data may not be completely written yet.
+    ///
+    /// [`Scanner::new_snapshot`]: crate::Scanner::new_snapshot
+    AboveSnapshotTip(BlockHash),
+    /// The block index has no block data for this block.
+    MissingBlockData(BlockHash),
+    /// The block index has no undo data for this block.
+    MissingUndoData(BlockHash),
+    /// A blk/rev file referenced by the block index is not on disk.
+    PrunedFile(PathBuf),
+    /// A blk/rev record does not start with the datadir's network magic.
+    BadMagic {
+        path: PathBuf,
+        offset: u64,
+        found: [u8; 4],
+    },
+    /// The checksum of an undo record doesn't match its contents.
+    UndoChecksumMismatch(BlockHash),
+    /// A database record or file record is malformed.
+    CorruptRecord(String),
+    /// The block index record was written by a Bitcoin Core version we can't read.
+    UnsupportedRecordVersion(u32),
+    /// A block, header or transaction failed to decode.
+    Decode(encode::Error),
+    /// Reading a file failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ScannerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScannerError::DatabaseLocked(path) => write!(
+                f,
+                "database {} is locked, please close bitcoin core first or open a snapshot",
+                path.display()
+            ),
+            ScannerError::Database(path, status) => {
+                write!(f, "error opening database {}: {}", path.display(), status)
+            }
+            ScannerError::IndexUnavailable(path) => {
+                write!(f, "index {} not found", path.display())
+            }
+            ScannerError::WrongNetwork { expected, found } => match found {
+                Some(found) => write!(f, "datadir is for {}, not {}", found, expected),
+                None => write!(f, "datadir is not for {}", expected),
+            },
+            ScannerError::SnapshotLink(path, e) => write!(
+                f,
+                "can't hard-link {} into the snapshot directory, is it on the same file system? {}",
+                path.display(),
+                e
+            ),
+            ScannerError::UnknownBlock(hash) => write!(f, "block {} not in block index", hash),
+            ScannerError::AboveSnapshotTip(hash) => {
+                write!(f, "block {} is above the snapshot tip", hash)
+            }
+            ScannerError::MissingBlockData(hash) => write!(f, "no block data for {}", hash),
+            ScannerError::MissingUndoData(hash) => write!(f, "no undo data for {}", hash),
+            ScannerError::PrunedFile(path) => write!(
+                f,
+                "{} not found, do you have pruning enabled?",
+                path.display()
+            ),
+            ScannerError::BadMagic {
+                path,
+                offset,
+                found,
+            } => write!(
+                f,
+                "unexpected magic {:02x?} at {} in {}",
+                found,
+                offset,
+                path.display()
+            ),
+            ScannerError::UndoChecksumMismatch(hash) => {
+                write!(f, "undo checksum mismatch for {}", hash)
+            }
+            ScannerError::CorruptRecord(msg) => write!(f, "corrupt record: {}", msg),
+            ScannerError::UnsupportedRecordVersion(version) => {
+                write!(f, "unsupported block index record version {}", version)
+            }
+            ScannerError::Decode(e) => write!(f, "decode error: {}", e),
+            ScannerError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScannerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScannerError::Database(_, status) => Some(status),
+            ScannerError::SnapshotLink(_, e) => Some(e),
+            ScannerError::Decode(e) => Some(e),
+            ScannerError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ScannerError {
+    fn from(e: io::Error) -> Self {
+        ScannerError::Io(e)
+    }
+}
+
+impl From<encode::Error> for ScannerError {
+    fn from(e: encode::Error) -> Self {
+        ScannerError::Decode(e)
+    }
+}
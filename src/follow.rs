@@ -0,0 +1,185 @@
+use std::{path::PathBuf, thread, time::Duration};
+
+use bitcoin::{Block, BlockHash, Network};
+
+use crate::{copy::DbState, BlockUndo, Scanner, ScannerError};
+
+/// A change of the active chain, see [`ChainFollower`].
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    /// The block was added on top of the chain. `undo` holds the outputs it
+    /// spends and is only `None` for genesis.
+    BlockConnected {
+        height: u32,
+        block: Block,
+        undo: Option<BlockUndo>,
+    },
+    /// The block was removed from the top of the chain by a reorg, the
+    /// outputs in `undo` are unspent again.
+    BlockDisconnected {
+        height: u32,
+        block: Block,
+        undo: Option<BlockUndo>,
+    },
+}
+
+/// Tails the chain of a running node.
+///
+/// Only the block index is read, its best validated block is the tip. Once
+/// all known blocks have been processed, the datadir is reopened when
+/// bitcoind next writes to the block index. If the last processed block has
+/// left the active chain, blocks are disconnected down to the fork first,
+/// then the new branch is connected. Persist [`ChainFollower::tip`] after
+/// handling an event to resume from it later.
+pub struct ChainFollower {
+    datadir: PathBuf,
+    network: Network,
+    snapshots: bool,
+    copy_dir: Option<PathBuf>,
+    poll_interval: Duration,
+    scanner: Option<Scanner>,
+    /// The block index as the scanner was opened.
+    index_state: Option<DbState>,
+    caught_up: bool,
+    tip: Option<BlockHash>,
+}
+
+impl ChainFollower {
+    /// Follows the chain from genesis.
+    pub fn new(datadir: PathBuf, network: Network) -> Self {
+        Self {
+            datadir,
+            network,
+            snapshots: true,
+            copy_dir: None,
+            poll_interval: Duration::from_secs(10),
+            scanner: None,
+            index_state: None,
+            caught_up: false,
+            tip: None,
+        }
+    }
+
+    /// Continues after `tip`, a block processed earlier. It doesn't need to
+    /// be in the active chain anymore.
+    pub fn resume_after(&mut self, tip: BlockHash) {
+        self.tip = Some(tip);
+    }
+
+    /// Whether to read a copy of the block index, see [`Scanner::new_snapshot`].
+    /// On by default, turn it off if bitcoind is not running.
+    pub fn use_snapshots(&mut self, snapshots: bool) {
+        self.snapshots = snapshots;
+    }
+
+    /// Where to copy the block index, see [`Scanner::new_snapshot_in`].
+    /// Defaults to the datadir.
+    pub fn set_copy_dir(&mut self, copy_dir: PathBuf) {
+        self.copy_dir = Some(copy_dir);
+    }
+
+    /// How long the iterator waits before looking for new blocks again.
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    /// The last block passed on in an event.
+    pub fn tip(&self) -> Option<BlockHash> {
+        self.tip
+    }
+
+    /// Returns the next event, or `None` once the follower caught up with
+    /// the node. Later calls reopen the datadir once bitcoind has written to
+    /// its block index, which it does when it flushes new blocks.
+    pub fn poll(&mut self) -> Result<Option<ChainEvent>, ScannerError> {
+        let index = self.datadir.join("blocks").join("index");
+        if self.caught_up {
+            if self.index_state.as_ref() == Some(&DbState::read(&index)?) {
+                return Ok(None);
+            }
+            self.reset();
+        }
+
+        let scanner = match &mut self.scanner {
+            Some(scanner) => scanner,
+            None => {
+                // taken first, a write while we copy is seen next time
+                self.index_state = Some(DbState::read(&index)?);
+                let copy_dir = match self.snapshots {
+                    true => Some(self.copy_dir.as_deref().unwrap_or(&self.datadir)),
+                    false => None,
+                };
+                let scanner =
+                    Scanner::open_block_index(self.datadir.clone(), self.network, copy_dir)?;
+                self.scanner.insert(scanner)
+            }
+        };
+
+        let chain = scanner.active_chain()?;
+        let (tip, event) = match self.tip {
+            Some(tip) if !chain.contains(&tip) => {
+                // it was connected before, so it is completely written even
+                // if it is above the new tip
+                let (record, block, undo) = scanner.read_block_with_undo_uncapped(&tip)?;
+                let prev = block.header.prev_blockhash;
+                let event = ChainEvent::BlockDisconnected {
+                    height: record.height,
+                    block,
+                    undo,
+                };
+                (prev, event)
+            }
+            tip => {
+                let height = match tip {
+                    Some(tip) => chain.height_of(&tip).unwrap() + 1,
+                    None => 0,
+                };
+                let hash = match chain.hash_at_height(height) {
+                    Some(hash) => hash,
+                    None => {
+                        self.caught_up = true;
+                        return Ok(None);
+                    }
+                };
+                let (record, block, undo) = scanner.read_block_with_undo(&hash)?;
+                let event = ChainEvent::BlockConnected {
+                    height: record.height,
+                    block,
+                    undo,
+                };
+                (hash, event)
+            }
+        };
+
+        self.tip = Some(tip);
+        Ok(Some(event))
+    }
+
+    /// Drops the scanner, the next poll reopens the datadir.
+    fn reset(&mut self) {
+        self.scanner = None;
+        self.index_state = None;
+        self.caught_up = false;
+    }
+}
+
+/// Blocks until the next event, polling the datadir every poll interval.
+/// Never ends.
+impl Iterator for ChainFollower {
+    type Item = Result<ChainEvent, ScannerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.poll() {
+                Ok(Some(event)) => return Some(Ok(event)),
+                Ok(None) => thread::sleep(self.poll_interval),
+                Err(e) => {
+                    // start over with a fresh view after a pause
+                    self.reset();
+                    thread::sleep(self.poll_interval);
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+
+use bitcoin::{hashes::Hash, BlockHash};
+use rusty_leveldb::DB;
+
+use crate::{copy::CopyDir, Scanner, ScannerError};
+
+/// The LevelDB of one of Bitcoin Core's optional indexes under `indexes/`.
+pub(crate) struct IndexDb {
+    db: DB,
+    obfs: Option<Vec<u8>>,
+}
+
+impl IndexDb {
+    /// Opens the index at `path`, or a copy of it if the scanner reads a snapshot.
+    pub(crate) fn open(path: PathBuf, copy: Option<&CopyDir>) -> Result<Self, ScannerError> {
+        if !path.is_dir() {
+            return Err(ScannerError::IndexUnavailable(path));
+        }
+        let path = match copy {
+            Some(copy) => copy.copy_db(&path)?,
+            None => path,
+        };
+        let (db, obfs) = Scanner::open_db(&path)?;
+        Ok(Self { db, obfs })
+    }
+
+    /// Gets and de-obfuscates the value stored at `key`.
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.db.get(key)?;
+        Some(Scanner::obfs(&self.obfs, &value))
+    }
+
+    /// Reads the value an index stores for a block. Values of blocks in the
+    /// active chain are keyed by `t` and the big-endian height, prefixed with
+    /// the block hash. After a reorg, values of disconnected blocks move to `s`
+    /// and the block hash.
+    pub(crate) fn get_block_value(&mut self, height: u32, hash: &BlockHash) -> Option<Vec<u8>> {
+        let key = [&b"t"[..], &height.to_be_bytes()[..]].concat();
+        if let Some(value) = self.get(&key) {
+            if value.len() >= 32 && value[..32] == hash.to_byte_array() {
+                return Some(value[32..].to_vec());
+            }
+        }
+
+        let key = [&b"s"[..], &hash.to_byte_array()[..]].concat();
+        self.get(&key)
+    }
+}
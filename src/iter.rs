@@ -0,0 +1,55 @@
+use bitcoin::BlockHash;
+
+use crate::{BlockIndexRecord, BlockUndo, Scanner, ScannerError};
+
+/// Reads a fixed list of blocks in order, see [`Scanner::blocks`].
+///
+/// Blocks whose data was pruned come as [`ScannerError::MissingBlockData`]
+/// or [`ScannerError::PrunedFile`] errors, unless [`Blocks::skip_pruned`]
+/// is set.
+pub struct Blocks<'a> {
+    scanner: &'a mut Scanner,
+    hashes: std::vec::IntoIter<BlockHash>,
+    skip_pruned: bool,
+}
+
+impl<'a> Blocks<'a> {
+    pub(crate) fn new(scanner: &'a mut Scanner, hashes: Vec<BlockHash>) -> Self {
+        Self {
+            scanner,
+            hashes: hashes.into_iter(),
+            skip_pruned: false,
+        }
+    }
+
+    /// Silently skips blocks whose data is no longer on disk.
+    pub fn skip_pruned(mut self) -> Self {
+        self.skip_pruned = true;
+        self
+    }
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<(BlockIndexRecord, bitcoin::Block, Option<BlockUndo>), ScannerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let hash = self.hashes.next()?;
+            match self.scanner.read_block_with_undo(&hash) {
+                Err(ScannerError::MissingBlockData(_) | ScannerError::PrunedFile(_))
+                    if self.skip_pruned =>
+                {
+                    continue
+                }
+                result => return Some(result),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.skip_pruned {
+            true => (0, self.hashes.size_hint().1),
+            false => self.hashes.size_hint(),
+        }
+    }
+}
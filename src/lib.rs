@@ -1,14 +1,56 @@
-use bitcoin::ScriptBuf;
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{self, Error, ErrorKind, Read, Result};
+use bitcoin::{
+    hashes::{sha256d, Hash, HashEngine},
+    secp256k1::PublicKey,
+    BlockHash, Script, ScriptBuf,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
 
-const _MAX_SIZE: u64 = 0x02000000;
+const MAX_SIZE: u64 = 0x02000000;
+mod audit;
+mod blockscan;
+mod chain;
+mod coin;
+mod coinstats;
+mod copy;
+mod error;
+mod filter;
+mod follow;
+mod index;
+mod iter;
+mod muhash;
+mod pipeline;
 mod scanner;
-pub use scanner::Scanner;
+mod snapshot;
+mod store;
+mod testnet4;
+mod txindex;
+mod view;
+mod xor;
+pub use audit::{block_subsidy, SupplyAudit};
+pub use blockscan::{RawBlockRecord, RawBlockScanner};
+pub use chain::{ActiveChain, ChainTip, ChainTipStatus};
+pub use coin::{Coin, Utxos};
+pub use coinstats::{
+    CoinStatsRecord, MuHash, UtxoSetHash, UtxoSetHashKind, UtxoSetHasher, UtxoSetStats,
+};
+pub use error::ScannerError;
+pub use filter::{compute_basic_filter, BlockFilterRecord};
+pub use follow::{ChainEvent, ChainFollower};
+pub use iter::Blocks;
+pub use muhash::MuHash3072;
+pub use pipeline::{Pipeline, ProcessedBlocks};
+pub use scanner::{BlockIndexRecord, BlockStatus, FileInformationRecord, Scanner};
+pub use snapshot::{SnapshotMetadata, SnapshotReader};
+pub use store::{BlockFileStore, RecordBytes};
+pub use testnet4::{testnet4_genesis_block, TESTNET4_MAGIC};
+pub use txindex::TransactionIndexRecord;
+pub use view::{BlockView, InputView, OutputView, Transactions, TxView};
+pub use xor::{XorKey, XorReader};
 
 pub mod db;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TxInUndo {
     pub coinbase: u64,
     pub height: u64,
@@ -16,9 +58,10 @@ pub struct TxInUndo {
     pub amount: u64,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct TxUndo(pub Vec<TxInUndo>);
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BlockUndo {
     pub inner: Vec<TxUndo>,
     pub dsha: [u8; 32],
@@ -29,7 +72,12 @@ impl BlockUndo {
         let ntxs = match ntxs {
             Some(ntxs) => {
                 let file_ntxs = read_compact_size(reader)? as u32 + 1;
-                assert_eq!(file_ntxs, ntxs);
+                if file_ntxs != ntxs {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("undo has {} txs, block has {}", file_ntxs, ntxs),
+                    ));
+                }
                 ntxs
             }
             None => read_compact_size(reader)? as u32 + 1,
@@ -49,55 +97,72 @@ impl BlockUndo {
 
         Ok(Self { inner, dsha })
     }
+
+    /// Writes the undo data followed by `dsha`, the layout `parse` reads.
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.serialize_body(writer)?;
+        writer.write_all(&self.dsha)
+    }
+
+    /// Writes a complete rev file record: magic, size, undo data and checksum.
+    pub fn serialize_record<W: Write>(&self, writer: &mut W, magic: [u8; 4]) -> io::Result<()> {
+        let mut body = vec![];
+        self.serialize_body(&mut body)?;
+        writer.write_all(&magic)?;
+        writer.write_u32::<LittleEndian>(body.len() as u32)?;
+        writer.write_all(&body)?;
+        writer.write_all(&self.dsha)
+    }
+
+    /// Sets `dsha` to the checksum Bitcoin Core would store for this undo data
+    /// when the block's parent is `prev_blockhash`.
+    pub fn update_checksum(&mut self, prev_blockhash: &BlockHash) {
+        let mut body = vec![];
+        self.serialize_body(&mut body).unwrap();
+        self.dsha = undo_checksum(prev_blockhash, &body);
+    }
+
+    fn serialize_body<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        // the coinbase has no undo data and is not serialized
+        write_compact_size(writer, self.inner.len().saturating_sub(1) as u64)?;
+        for tx_undo in self.inner.iter().skip(1) {
+            tx_undo.serialize(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl TxUndo {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_compact_size(writer, self.0.len() as u64)?;
+        for txin_undo in &self.0 {
+            txin_undo.serialize(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bitcoin Core's rev record checksum: double-SHA256 of the previous block
+/// hash followed by the serialized undo data.
+fn undo_checksum(prev_blockhash: &BlockHash, undo: &[u8]) -> [u8; 32] {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&prev_blockhash.to_byte_array());
+    engine.input(undo);
+    sha256d::Hash::from_engine(engine).to_byte_array()
 }
 
 impl TxInUndo {
-    fn parse<R: Read>(reader: &mut R) -> Result<Self> {
+    pub fn parse<R: Read>(reader: &mut R) -> Result<Self> {
         let code = read_varint_core(reader)?;
         let coinbase = code & 1;
         let height = code >> 1;
 
-        let version = read_varint_core(reader)?;
-        assert_eq!(version, 0);
-        let amount = decompress_amount(read_varint_core(reader)?);
-        let kind = read_varint_core(reader)?;
-
-        let script = match kind {
-            0 => {
-                // p2pkh
-                let mut script = vec![0x76, 0xa9, 20];
-                let mut buf = vec![0; 20];
-                reader.read_exact(&mut buf)?;
-                script.extend_from_slice(&buf);
-                script.extend_from_slice(&[0x88, 0xac]);
-                script
-            }
-            1 => {
-                // p2sh
-                let mut script = vec![0xa9, 20];
-                let mut buf = vec![0; 20];
-                reader.read_exact(&mut buf)?;
-                script.extend_from_slice(&buf);
-                script.push(0x87);
-                script
-            }
-            2..=5 => {
-                // TODO: decompress pubkey
-                // p2pk, fake implementaion! decompressing pubkey not implemented
-                let sz = 32;
-                let mut script = vec![0; sz];
-                reader.read_exact(&mut script)?;
-                script
-            }
-            _ => {
-                let sz = (kind - 6) as usize;
-                let mut script = vec![0; sz];
-                reader.read_exact(&mut script)?;
-                script
-            }
-        };
-
-        let script: ScriptBuf = ScriptBuf::from(script);
+        // nVersionDummy: before 0.15 the tx version was stored here, it is
+        // ignored like Bitcoin Core does
+        if height > 0 {
+            read_varint_core(reader)?;
+        }
+        let (amount, script) = read_compressed_txout(reader)?;
 
         Ok(Self {
             coinbase,
@@ -106,9 +171,129 @@ impl TxInUndo {
             amount,
         })
     }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_varint_core(writer, self.height * 2 + self.coinbase)?;
+        if self.height > 0 {
+            writer.write_all(&[0])?;
+        }
+        write_compressed_txout(writer, self.amount, &self.script)
+    }
 }
 
-fn read_compact_size<R: Read>(r: &mut R) -> Result<u64> {
+/// Reads an amount and script as compressed by Bitcoin Core's `TxOutCompression`.
+pub fn read_compressed_txout<R: Read>(reader: &mut R) -> Result<(u64, ScriptBuf)> {
+    let amount = decompress_amount(read_varint_core(reader)?);
+    let script = read_compressed_script(reader)?;
+    Ok((amount, script))
+}
+
+pub fn read_compressed_script<R: Read>(reader: &mut R) -> Result<ScriptBuf> {
+    let kind = read_varint_core(reader)?;
+
+    let script = match kind {
+        0 => {
+            // p2pkh
+            let mut script = vec![0x76, 0xa9, 20];
+            let mut buf = vec![0; 20];
+            reader.read_exact(&mut buf)?;
+            script.extend_from_slice(&buf);
+            script.extend_from_slice(&[0x88, 0xac]);
+            script
+        }
+        1 => {
+            // p2sh
+            let mut script = vec![0xa9, 20];
+            let mut buf = vec![0; 20];
+            reader.read_exact(&mut buf)?;
+            script.extend_from_slice(&buf);
+            script.push(0x87);
+            script
+        }
+        2 | 3 => {
+            // p2pk, compressed pubkey: the kind is the pubkey prefix
+            let mut script = vec![33, kind as u8];
+            let mut buf = vec![0; 32];
+            reader.read_exact(&mut buf)?;
+            script.extend_from_slice(&buf);
+            script.push(0xac);
+            script
+        }
+        4 | 5 => {
+            // p2pk, uncompressed pubkey: stored as x and the parity of y
+            let mut compressed = [0; 33];
+            compressed[0] = kind as u8 - 2;
+            reader.read_exact(&mut compressed[1..])?;
+            let pubkey = PublicKey::from_slice(&compressed)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let mut script = vec![65];
+            script.extend_from_slice(&pubkey.serialize_uncompressed());
+            script.push(0xac);
+            script
+        }
+        _ => {
+            let sz = kind - 6;
+            if sz > MAX_SIZE {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("script size {}", sz),
+                ));
+            }
+            let mut script = vec![0; sz as usize];
+            reader.read_exact(&mut script)?;
+            script
+        }
+    };
+
+    Ok(ScriptBuf::from(script))
+}
+
+/// Writes an amount and script compressed like Bitcoin Core's `TxOutCompression`.
+pub fn write_compressed_txout<W: Write>(
+    writer: &mut W,
+    amount: u64,
+    script: &Script,
+) -> Result<()> {
+    write_varint_core(writer, compress_amount(amount))?;
+    write_compressed_script(writer, script)
+}
+
+/// Writes a script in Bitcoin Core's `ScriptCompression` format: standard
+/// P2PKH, P2SH and P2PK scripts as a kind byte plus 20 or 32 bytes, anything
+/// else as its size plus 6 followed by the raw script.
+pub fn write_compressed_script<W: Write>(writer: &mut W, script: &Script) -> Result<()> {
+    match compress_script(script) {
+        Some(compressed) => writer.write_all(&compressed),
+        None => {
+            let bytes = script.as_bytes();
+            write_varint_core(writer, bytes.len() as u64 + 6)?;
+            writer.write_all(bytes)
+        }
+    }
+}
+
+fn compress_script(script: &Script) -> Option<Vec<u8>> {
+    let b = script.as_bytes();
+    if script.is_p2pkh() {
+        Some([&[0x00], &b[3..23]].concat())
+    } else if script.is_p2sh() {
+        Some([&[0x01], &b[2..22]].concat())
+    } else if b.len() == 35 && b[0] == 33 && b[34] == 0xac && (b[1] == 0x02 || b[1] == 0x03) {
+        Some(b[1..34].to_vec())
+    } else if b.len() == 67
+        && b[0] == 65
+        && b[66] == 0xac
+        && b[1] == 0x04
+        && PublicKey::from_slice(&b[1..66]).is_ok()
+    {
+        // only valid points can be recovered from x and the parity of y
+        Some([&[0x04 | (b[65] & 1)], &b[2..34]].concat())
+    } else {
+        None
+    }
+}
+
+pub fn read_compact_size<R: Read>(r: &mut R) -> Result<u64> {
     let n = r.read_u8()?;
     match n {
         0xFF => {
@@ -139,7 +324,25 @@ fn read_compact_size<R: Read>(r: &mut R) -> Result<u64> {
     }
 }
 
-fn read_varint_core<R: Read>(r: &mut R) -> Result<u64> {
+pub fn write_compact_size<W: Write>(w: &mut W, n: u64) -> Result<()> {
+    match n {
+        0..=0xFC => w.write_u8(n as u8),
+        0xFD..=0xFFFF => {
+            w.write_u8(0xFD)?;
+            w.write_u16::<LittleEndian>(n as u16)
+        }
+        0x10000..=0xFFFF_FFFF => {
+            w.write_u8(0xFE)?;
+            w.write_u32::<LittleEndian>(n as u32)
+        }
+        _ => {
+            w.write_u8(0xFF)?;
+            w.write_u64::<LittleEndian>(n)
+        }
+    }
+}
+
+pub fn read_varint_core<R: Read>(r: &mut R) -> Result<u64> {
     let mut n: u64 = 0;
     loop {
         let mut ch_data = [0; 1];
@@ -154,6 +357,22 @@ fn read_varint_core<R: Read>(r: &mut R) -> Result<u64> {
     }
 }
 
+/// Writes Bitcoin Core's `VARINT`, the inverse of [`read_varint_core`].
+pub fn write_varint_core<W: Write>(w: &mut W, mut n: u64) -> Result<()> {
+    let mut tmp = [0u8; 10];
+    let mut len = 0;
+    loop {
+        tmp[len] = (n & 0x7F) as u8 | if len > 0 { 0x80 } else { 0x00 };
+        if n <= 0x7F {
+            break;
+        }
+        n = (n >> 7) - 1;
+        len += 1;
+    }
+    tmp[..=len].reverse();
+    w.write_all(&tmp[..=len])
+}
+
 // Amount compression:
 // * If the amount is 0, output 0
 // * first, divide the amount (in base units) by the largest power of 10 possible; call the exponent e (e is max 9)
@@ -162,8 +381,7 @@ fn read_varint_core<R: Read>(r: &mut R) -> Result<u64> {
 //   * output 1 + 10*(9*n + d - 1) + e
 // * if e==9, we only know the resulting number is not zero, so output 1 + 10*(n - 1) + 9
 // (this is decodable, as d is in [1-9] and e is in [0-9])
-#[allow(dead_code)]
-fn compress_amount(n: u64) -> u64 {
+pub fn compress_amount(n: u64) -> u64 {
     if n == 0 {
         return 0;
     }
@@ -183,7 +401,7 @@ fn compress_amount(n: u64) -> u64 {
     }
 }
 
-fn decompress_amount(x: u64) -> u64 {
+pub fn decompress_amount(x: u64) -> u64 {
     // x = 0  OR  x = 1+10*(9*n + d - 1) + e  OR  x = 1+10*(n - 1) + 9
     if x == 0 {
         return 0;
@@ -203,3 +421,206 @@ fn decompress_amount(x: u64) -> u64 {
     };
     (0..e).fold(n, |acc, _| acc * 10)
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        blockdata::constants::genesis_block,
+        consensus::serialize,
+        secp256k1::{Secp256k1, SecretKey},
+        Network,
+    };
+
+    use super::*;
+    use crate::scanner::{parse_undo_record, BlockIndexRecord};
+
+    #[test]
+    fn varint_boundaries() {
+        for (n, bytes) in [
+            (0, &[0x00][..]),
+            (0x7f, &[0x7f]),
+            (0x80, &[0x80, 0x00]),
+            (0x407f, &[0xff, 0x7f]),
+            (0x4080, &[0x80, 0x80, 0x00]),
+        ] {
+            let mut buf = vec![];
+            write_varint_core(&mut buf, n).unwrap();
+            assert_eq!(buf, bytes, "{}", n);
+            assert_eq!(read_varint_core(&mut &buf[..]).unwrap(), n);
+        }
+        for n in [0xffff, 0x10000, 1 << 32, u64::MAX] {
+            let mut buf = vec![];
+            write_varint_core(&mut buf, n).unwrap();
+            assert_eq!(read_varint_core(&mut &buf[..]).unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn compact_size_boundaries() {
+        for (n, len) in [
+            (0xfc, 1),
+            (0xfd, 3),
+            (0xffff, 3),
+            (0x10000, 5),
+            (0xffff_ffff, 5),
+            (1 << 32, 9),
+        ] {
+            let mut buf = vec![];
+            write_compact_size(&mut buf, n).unwrap();
+            assert_eq!(buf.len(), len, "{}", n);
+            assert_eq!(read_compact_size(&mut &buf[..]).unwrap(), n);
+        }
+        // non-canonical encodings are rejected
+        assert!(read_compact_size(&mut &[0xfd, 0xfc, 0x00][..]).is_err());
+        assert!(read_compact_size(&mut &[0xfe, 0xff, 0xff, 0x00, 0x00][..]).is_err());
+    }
+
+    #[test]
+    fn amount_compression() {
+        for (n, compressed) in [
+            (0, 0),
+            (1, 1),
+            (1_000_000, 7),
+            (100_000_000, 9),
+            (5_000_000_000, 50),
+            (2_100_000_000_000_000, 21_000_000),
+        ] {
+            assert_eq!(compress_amount(n), compressed, "{}", n);
+            assert_eq!(decompress_amount(compressed), n);
+        }
+        for n in [
+            12_345,
+            999_999_999,
+            1_234_567_890_123,
+            20_999_999_997_690_000,
+        ] {
+            assert_eq!(decompress_amount(compress_amount(n)), n);
+        }
+    }
+
+    fn p2pk(pubkey: &[u8]) -> ScriptBuf {
+        ScriptBuf::from([&[pubkey.len() as u8], pubkey, &[0xac]].concat())
+    }
+
+    /// One script of every kind `ScriptCompression` handles, with the kind
+    /// it is stored as.
+    pub(crate) fn scripts() -> Vec<(ScriptBuf, u64)> {
+        let secp = Secp256k1::new();
+        let pubkeys: Vec<_> = (1..=8u8)
+            .map(|i| {
+                let mut secret = [0; 32];
+                secret[31] = i;
+                let secret = SecretKey::from_slice(&secret).unwrap();
+                PublicKey::from_secret_key(&secp, &secret)
+            })
+            .collect();
+        let compressed = |prefix| {
+            let pubkey = pubkeys.iter().find(|pk| pk.serialize()[0] == prefix);
+            pubkey.unwrap().serialize()
+        };
+        let uncompressed = |parity| {
+            let pubkey = pubkeys
+                .iter()
+                .find(|pk| pk.serialize_uncompressed()[64] & 1 == parity);
+            pubkey.unwrap().serialize_uncompressed()
+        };
+
+        let hash = [0xab; 20];
+        vec![
+            (
+                ScriptBuf::from([&[0x76, 0xa9, 20][..], &hash, &[0x88, 0xac]].concat()),
+                0,
+            ),
+            (
+                ScriptBuf::from([&[0xa9, 20][..], &hash, &[0x87]].concat()),
+                1,
+            ),
+            (p2pk(&compressed(2)), 2),
+            (p2pk(&compressed(3)), 3),
+            (p2pk(&uncompressed(0)), 4),
+            (p2pk(&uncompressed(1)), 5),
+            (ScriptBuf::from(vec![0x00, 0x14, 0xab, 0xcd]), 4 + 6),
+            (ScriptBuf::new(), 6),
+        ]
+    }
+
+    #[test]
+    fn script_compression_roundtrip() {
+        for (script, kind) in scripts() {
+            let mut buf = vec![];
+            write_compressed_script(&mut buf, &script).unwrap();
+            assert_eq!(read_varint_core(&mut &buf[..]).unwrap(), kind, "{}", script);
+            assert_eq!(read_compressed_script(&mut &buf[..]).unwrap(), script);
+        }
+
+        // a point not on the curve can't be recovered and is stored raw
+        let mut invalid = scripts()[4].0.to_bytes();
+        invalid[40] ^= 1;
+        let mut buf = vec![];
+        write_compressed_script(&mut buf, Script::from_bytes(&invalid)).unwrap();
+        assert_eq!(read_varint_core(&mut &buf[..]).unwrap(), 67 + 6);
+        assert_eq!(
+            read_compressed_script(&mut &buf[..]).unwrap().as_bytes(),
+            invalid
+        );
+    }
+
+    #[test]
+    fn block_undo_roundtrip() {
+        let mut inner = vec![TxUndo::default()];
+        for (i, (script, _)) in scripts().into_iter().enumerate() {
+            inner.push(TxUndo(vec![TxInUndo {
+                coinbase: i as u64 % 2,
+                height: i as u64 * 1000,
+                script,
+                amount: i as u64 * 12_345,
+            }]));
+        }
+        let mut undo = BlockUndo {
+            inner,
+            dsha: [0; 32],
+        };
+        let genesis = genesis_block(Network::Regtest);
+        undo.update_checksum(&genesis.header.prev_blockhash);
+
+        let mut buf = vec![];
+        undo.serialize(&mut buf).unwrap();
+        let mut record = vec![];
+        undo.serialize_record(&mut record, [0xfa, 0xbf, 0xb5, 0xda])
+            .unwrap();
+        assert_eq!(&record[8..], &buf[..]);
+        assert_eq!(
+            u32::from_le_bytes(record[4..8].try_into().unwrap()) as usize,
+            buf.len() - 32
+        );
+
+        // a block index entry at height 1 without data, followed by its header
+        let mut value = vec![];
+        for n in [250_000, 1, 0, undo.inner.len() as u64] {
+            write_varint_core(&mut value, n).unwrap();
+        }
+        value.extend(serialize(&genesis.header));
+        let index_record = BlockIndexRecord::parse(&value).unwrap();
+
+        assert_eq!(parse_undo_record(&buf, &index_record, true).unwrap(), undo);
+        buf[0] ^= 1;
+        assert!(matches!(
+            parse_undo_record(&buf, &index_record, true),
+            Err(ScannerError::UndoChecksumMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn undo_with_tx_version() {
+        // height 120 from a coinbase, written before 0.15 with nVersion 1
+        let script = scripts().remove(0).0;
+        let mut buf = vec![];
+        write_varint_core(&mut buf, 120 * 2 + 1).unwrap();
+        write_varint_core(&mut buf, 1).unwrap();
+        write_compressed_txout(&mut buf, 5_000_000_000, &script).unwrap();
+
+        let undo = TxInUndo::parse(&mut &buf[..]).unwrap();
+        assert_eq!((undo.coinbase, undo.height), (1, 120));
+        assert_eq!((undo.amount, undo.script), (5_000_000_000, script));
+    }
+}
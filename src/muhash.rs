@@ -0,0 +1,252 @@
+use bitcoin::hashes::{sha256, Hash};
+
+use crate::MuHash;
+
+const LIMBS: usize = 48;
+const BYTE_SIZE: usize = LIMBS * 8;
+/// The MuHash3072 prime is 2^3072 - PRIME_DIFF.
+const PRIME_DIFF: u64 = 1103717;
+
+/// An unsigned 3072-bit number as little-endian 64-bit limbs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Num3072([u64; LIMBS]);
+
+impl Num3072 {
+    fn one() -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = 1;
+        Self(limbs)
+    }
+
+    fn from_bytes(bytes: &[u8; BYTE_SIZE]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Self(limbs)
+    }
+
+    fn to_bytes(self) -> [u8; BYTE_SIZE] {
+        let mut bytes = [0; BYTE_SIZE];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.0) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Whether the number is at least the prime.
+    fn is_overflow(&self) -> bool {
+        self.0[0] > u64::MAX - PRIME_DIFF && self.0[1..].iter().all(|&limb| limb == u64::MAX)
+    }
+
+    /// Adds `n` modulo 2^3072, returning whether it wrapped.
+    fn add_small(&mut self, n: u64) -> bool {
+        let mut carry = n as u128;
+        for limb in self.0.iter_mut() {
+            if carry == 0 {
+                break;
+            }
+            let sum = *limb as u128 + carry;
+            *limb = sum as u64;
+            carry = sum >> 64;
+        }
+        carry != 0
+    }
+
+    /// Multiplies modulo the prime, the result is fully reduced.
+    fn mul(&self, other: &Self) -> Self {
+        let mut wide = [0u64; 2 * LIMBS];
+        for (i, &a) in self.0.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, &b) in other.0.iter().enumerate() {
+                let cur = a as u128 * b as u128 + wide[i + j] as u128 + carry;
+                wide[i + j] = cur as u64;
+                carry = cur >> 64;
+            }
+            wide[i + LIMBS] = carry as u64;
+        }
+
+        // high * 2^3072 is congruent to high * PRIME_DIFF
+        let mut out = [0u64; LIMBS];
+        let mut carry = 0u128;
+        for i in 0..LIMBS {
+            let cur = wide[i] as u128 + wide[i + LIMBS] as u128 * PRIME_DIFF as u128 + carry;
+            out[i] = cur as u64;
+            carry = cur >> 64;
+        }
+        let mut out = Num3072(out);
+        if out.add_small((carry * PRIME_DIFF as u128) as u64) {
+            out.add_small(PRIME_DIFF);
+        }
+        if out.is_overflow() {
+            out.add_small(PRIME_DIFF);
+        }
+        out
+    }
+
+    /// The inverse modulo the prime, by Fermat's little theorem.
+    fn inverse(&self) -> Self {
+        // p - 2
+        let mut exponent = [u64::MAX; LIMBS];
+        exponent[0] = u64::MAX - PRIME_DIFF - 1;
+
+        let mut out = Self::one();
+        for limb in exponent.iter().rev() {
+            for bit in (0..64).rev() {
+                out = out.mul(&out);
+                if (limb >> bit) & 1 == 1 {
+                    out = out.mul(self);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Bitcoin Core's MuHash3072 multiset hash, used for the `muhash` UTXO set
+/// commitment. Elements can be added and removed in any order.
+#[derive(Clone)]
+pub struct MuHash3072 {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl Default for MuHash3072 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MuHash3072 {
+    /// The hash of the empty set.
+    pub fn new() -> Self {
+        Self {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = self.numerator.mul(&to_num3072(data));
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = self.denominator.mul(&to_num3072(data));
+    }
+
+    pub fn finalize(&self) -> MuHash {
+        let num = self.numerator.mul(&self.denominator.inverse());
+        MuHash::from_raw_hash(sha256::Hash::hash(&num.to_bytes()))
+    }
+}
+
+/// Maps data to a 3072-bit number: the ChaCha20 keystream keyed with its SHA256.
+fn to_num3072(data: &[u8]) -> Num3072 {
+    let key = sha256::Hash::hash(data).to_byte_array();
+    let mut bytes = [0; BYTE_SIZE];
+    chacha20_keystream(&key, &mut bytes);
+    Num3072::from_bytes(&bytes)
+}
+
+/// ChaCha20 keystream with a zero nonce, starting at block 0.
+fn chacha20_keystream(key: &[u8; 32], out: &mut [u8]) {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (word, chunk) in input[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+
+    for (counter, block) in out.chunks_mut(64).enumerate() {
+        input[12] = counter as u32;
+        let mut x = input;
+        for _ in 0..10 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+        let words = x.iter().zip(input.iter()).map(|(x, i)| x.wrapping_add(*i));
+        for (chunk, word) in block.chunks_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32 byte element with `i` as its first byte, like `FromInt` in
+    /// Bitcoin Core's muhash_tests.
+    fn from_int(i: u8) -> [u8; 32] {
+        let mut data = [0; 32];
+        data[0] = i;
+        data
+    }
+
+    #[test]
+    fn bitcoin_core_vector() {
+        let mut acc = MuHash3072::new();
+        acc.insert(&from_int(0));
+        acc.insert(&from_int(1));
+        acc.remove(&from_int(2));
+        assert_eq!(
+            acc.finalize().to_string(),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+
+        // the order of insertions and removals doesn't matter
+        let mut acc2 = MuHash3072::new();
+        acc2.remove(&from_int(2));
+        acc2.insert(&from_int(1));
+        acc2.insert(&from_int(0));
+        assert_eq!(acc2.finalize(), acc.finalize());
+
+        let mut empty = MuHash3072::new();
+        empty.insert(&from_int(5));
+        empty.remove(&from_int(5));
+        assert_eq!(empty.finalize(), MuHash3072::new().finalize());
+    }
+
+    #[test]
+    fn chacha20_rfc8439() {
+        // RFC 8439 A.1 test vectors 1 and 2: zero key and nonce, blocks 0 and 1
+        let mut out = [0; 128];
+        chacha20_keystream(&[0; 32], &mut out);
+        let expected = "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+                        da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586\
+                        9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+                        29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f";
+        let hex: String = out.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, expected);
+    }
+
+    #[test]
+    fn inverse() {
+        for i in 0..4 {
+            let n = to_num3072(&from_int(i));
+            assert!(n.mul(&n.inverse()) == Num3072::one());
+        }
+
+        // p - 1 is -1, its square is 1
+        let mut minus_one = Num3072([u64::MAX; LIMBS]);
+        minus_one.0[0] = u64::MAX - PRIME_DIFF;
+        assert!(minus_one.mul(&minus_one) == Num3072::one());
+        assert!(minus_one.inverse() == minus_one);
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    ops::RangeBounds,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use bitcoin::{consensus::deserialize, Block};
+
+use crate::{
+    scanner::parse_undo_record, store::RecordBytes, BlockFileStore, BlockIndexRecord, BlockUndo,
+    Scanner, ScannerError,
+};
+
+/// Settings for [`Scanner::process_blocks`].
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    io_threads: usize,
+    workers: usize,
+    window: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    /// Two IO threads and a worker per CPU.
+    pub fn new() -> Self {
+        let workers = thread::available_parallelism().map_or(4, |n| n.get());
+        Self {
+            io_threads: 2,
+            workers,
+            window: workers * 8,
+        }
+    }
+
+    /// Threads reading blk/rev records.
+    pub fn io_threads(mut self, io_threads: usize) -> Self {
+        self.io_threads = io_threads.max(1);
+        self
+    }
+
+    /// Threads decoding blocks and running the closure.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// The most blocks read but not returned yet. Bounds memory use to about
+    /// this many blocks with their undo data and results.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+}
+
+/// A block to read with its file number and offsets, or why it can't be.
+type Job = Result<(BlockIndexRecord, (u32, u64, Option<u64>)), ScannerError>;
+type Raw = (BlockIndexRecord, RecordBytes, Option<RecordBytes>);
+type Outcome<T> = Result<Result<T, ScannerError>, Box<dyn Any + Send>>;
+
+impl Scanner {
+    /// Runs `f` on every block of the active chain in `range`, in parallel.
+    ///
+    /// IO threads read the raw records, workers decode them and call `f`
+    /// with the height, block and undo data (`None` for genesis). Results
+    /// come back in height order. A panic in `f` is passed on to the thread
+    /// iterating the results.
+    pub fn process_blocks<R, T, F>(
+        &mut self,
+        range: R,
+        pipeline: &Pipeline,
+        f: F,
+    ) -> Result<ProcessedBlocks<T>, ScannerError>
+    where
+        R: RangeBounds<u32>,
+        T: Send + 'static,
+        F: Fn(u32, Block, Option<BlockUndo>) -> T + Send + Sync + 'static,
+    {
+        // the scanner isn't Send, look everything up front
+        let mut jobs = vec![];
+        for hash in self.active_chain_range(range)? {
+            let job = self.block_index_record(&hash).and_then(|record| {
+                let locations = self.record_locations(&record)?;
+                Ok((record, locations))
+            });
+            jobs.push(job);
+        }
+
+        let store = self.block_file_store();
+        let verify_undo = self.verify_undo;
+        let read = move |job: Job| read_raw(&store, job);
+        let work = move |raw: Result<Raw, ScannerError>| {
+            let (record, block, undo) = raw?;
+            let block: Block = deserialize(&block)?;
+            let undo = match undo {
+                Some(buf) => Some(parse_undo_record(&buf, &record, verify_undo)?),
+                None => None,
+            };
+            Ok(f(record.height, block, undo))
+        };
+        Ok(run(jobs, pipeline, read, work))
+    }
+}
+
+/// Runs `read` on the IO threads and `work` on the workers for every job,
+/// returning the results in the order of `jobs`.
+fn run<J, I, T, R, W>(jobs: Vec<J>, pipeline: &Pipeline, read: R, work: W) -> ProcessedBlocks<T>
+where
+    J: Send + 'static,
+    I: Send + 'static,
+    T: Send + 'static,
+    R: Fn(J) -> I + Send + Sync + 'static,
+    W: Fn(I) -> Result<T, ScannerError> + Send + Sync + 'static,
+{
+    let len = jobs.len();
+    let jobs: Arc<Vec<_>> = Arc::new(jobs.into_iter().map(|job| Mutex::new(Some(job))).collect());
+    let next_job = Arc::new(AtomicUsize::new(0));
+
+    // a token per block in flight, returned once its result is taken
+    let (token_tx, token_rx) = sync_channel(pipeline.window);
+    for _ in 0..pipeline.window {
+        token_tx.send(()).unwrap();
+    }
+    let token_rx = Arc::new(Mutex::new(token_rx));
+    let (raw_tx, raw_rx) = sync_channel::<(usize, I)>(pipeline.window);
+    let raw_rx = Arc::new(Mutex::new(raw_rx));
+    let (result_tx, result_rx) = channel();
+
+    let read = Arc::new(read);
+    let mut handles = vec![];
+    for _ in 0..pipeline.io_threads {
+        let (jobs, next_job, token_rx, raw_tx, read) = (
+            jobs.clone(),
+            next_job.clone(),
+            token_rx.clone(),
+            raw_tx.clone(),
+            read.clone(),
+        );
+        handles.push(thread::spawn(move || loop {
+            if token_rx.lock().unwrap().recv().is_err() {
+                break;
+            }
+            let i = next_job.fetch_add(1, Ordering::SeqCst);
+            let job = match jobs.get(i) {
+                Some(job) => job.lock().unwrap().take().unwrap(),
+                None => break,
+            };
+            if raw_tx.send((i, read(job))).is_err() {
+                break;
+            }
+        }));
+    }
+    drop(raw_tx);
+
+    let work = Arc::new(work);
+    for _ in 0..pipeline.workers {
+        let (raw_rx, result_tx, work) = (raw_rx.clone(), result_tx.clone(), work.clone());
+        handles.push(thread::spawn(move || loop {
+            let (i, raw) = match raw_rx.lock().unwrap().recv() {
+                Ok(raw) => raw,
+                Err(_) => break,
+            };
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| work(raw)));
+            if result_tx.send((i, outcome)).is_err() {
+                break;
+            }
+        }));
+    }
+
+    ProcessedBlocks {
+        next: 0,
+        len,
+        pending: HashMap::new(),
+        results: Some(result_rx),
+        tokens: Some(token_tx),
+        handles,
+    }
+}
+
+fn read_raw(store: &BlockFileStore, job: Job) -> Result<Raw, ScannerError> {
+    let (record, (file, block_offset, undo_offset)) = job?;
+    let block = store.block_record(file, block_offset)?;
+    let undo = match undo_offset {
+        Some(offset) => Some(store.undo_record(file, offset)?),
+        None => None,
+    };
+    Ok((record, block, undo))
+}
+
+/// The results of [`Scanner::process_blocks`] in height order. Dropping it
+/// stops the pipeline.
+pub struct ProcessedBlocks<T> {
+    next: usize,
+    len: usize,
+    pending: HashMap<usize, Outcome<T>>,
+    results: Option<Receiver<(usize, Outcome<T>)>>,
+    tokens: Option<SyncSender<()>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl<T> Iterator for ProcessedBlocks<T> {
+    type Item = Result<T, ScannerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.len {
+            return None;
+        }
+        let outcome = loop {
+            if let Some(outcome) = self.pending.remove(&self.next) {
+                break outcome;
+            }
+            let (i, outcome) = self.results.as_ref()?.recv().ok()?;
+            self.pending.insert(i, outcome);
+        };
+
+        self.next += 1;
+        if let Some(tokens) = &self.tokens {
+            let _ = tokens.send(());
+        }
+        match outcome {
+            Ok(result) => Some(result),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T> Drop for ProcessedBlocks<T> {
+    fn drop(&mut self) {
+        // IO threads stop without tokens, workers once results can't be sent
+        self.tokens = None;
+        self.results = None;
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+
+    /// Sleeps for a few milliseconds, differently for each job, so workers
+    /// finish out of order.
+    fn jitter(i: u32) {
+        thread::sleep(Duration::from_millis((i as u64 * 7919) % 13));
+    }
+
+    #[test]
+    fn results_in_order() {
+        let pipeline = Pipeline::new().io_threads(2).workers(4).window(8);
+        let results = run(
+            (0..200).collect(),
+            &pipeline,
+            |i: u32| i,
+            |i| {
+                jitter(i);
+                Ok(i * 2)
+            },
+        );
+        assert_eq!(results.size_hint(), (200, Some(200)));
+        let results: Vec<u32> = results.map(Result::unwrap).collect();
+        assert_eq!(results, (0..200).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn window_of_one() {
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let pipeline = Pipeline::new().io_threads(2).workers(4).window(1);
+            let results = run(
+                (0..50).collect(),
+                &pipeline,
+                |i: u32| i,
+                |i| {
+                    jitter(i);
+                    Ok(i)
+                },
+            );
+            let _ = done_tx.send(results.map(Result::unwrap).collect::<Vec<_>>());
+        });
+        let results = done_rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("pipeline deadlocked");
+        assert_eq!(results, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn panic_reaches_consumer() {
+        let pipeline = Pipeline::new().io_threads(2).workers(4).window(4);
+        let mut results = run(
+            (0..20).collect(),
+            &pipeline,
+            |i: u32| i,
+            |i| {
+                jitter(i);
+                if i == 5 {
+                    panic!("job 5 failed");
+                }
+                Ok(i)
+            },
+        );
+        for i in 0..5 {
+            assert_eq!(results.next().unwrap().unwrap(), i);
+        }
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| results.next())).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"job 5 failed"));
+
+        // the other threads stop and are joined
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            drop(results);
+            let _ = done_tx.send(());
+        });
+        done_rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("drop didn't join the threads");
+    }
+}
@@ -1,14 +1,33 @@
 extern crate rusty_leveldb;
-use bitcoin::{blockdata::block::Header, consensus::Decodable, hashes::Hash};
+use bitcoin::{
+    blockdata::block::Header,
+    consensus::{deserialize, Decodable},
+    hashes::Hash,
+    network::constants::Magic,
+    Network, OutPoint, Work,
+};
 use rusty_leveldb::{LdbIterator, Options, DB};
 
 use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
     io::Cursor,
-    io::{BufReader, Read, Seek, SeekFrom},
-    path::PathBuf,
+    io::{BufReader, ErrorKind, Read},
+    ops::{Bound, RangeBounds, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::{read_varint_core, BlockUndo};
+use crate::{
+    coin::coin_key,
+    copy::CopyDir,
+    index::IndexDb,
+    read_varint_core,
+    store::{BlockFileStore, RecordBytes},
+    testnet4::chains,
+    undo_checksum, ActiveChain, BlockUndo, Blocks, ChainTip, ChainTipStatus, Coin, ScannerError,
+    Utxos, XorKey, XorReader, MAX_SIZE,
+};
 
 // Define the structs
 #[derive(Debug)]
@@ -23,37 +42,135 @@ pub struct BlockIndexRecord {
     pub header: Header,
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct FileInformationRecord {
-    num_blocks: u32,
-    block_file_size: u64,
-    undo_file_size: u64,
-    lowest_height: u32,
-    highest_height: u32,
-    lowest_timestamp: u32,
-    highest_timestamp: u32,
+impl BlockIndexRecord {
+    pub(crate) fn parse(value: &[u8]) -> Result<Self, ScannerError> {
+        let mut r = Cursor::new(value);
+        let read_varint_core = |r: &mut Cursor<&[u8]>| {
+            read_varint_core(r)
+                .map_err(|_| ScannerError::CorruptRecord("truncated block index record".into()))
+        };
+
+        let record_version = read_varint_core(&mut r)? as u32;
+        // test the code base with earlier versions and see if it works
+        // please PR if it does
+        if record_version < 220000 {
+            return Err(ScannerError::UnsupportedRecordVersion(record_version));
+        }
+        let height = read_varint_core(&mut r)? as u32;
+        let validation_status = read_varint_core(&mut r)? as u32;
+        let validation_status = BlockStatus::from_bits_truncate(validation_status);
+        let num_transactions = read_varint_core(&mut r)? as u32;
+
+        let file = if validation_status.contains(BlockStatus::BLOCK_HAVE_DATA)
+            || validation_status.contains(BlockStatus::BLOCK_HAVE_UNDO)
+        {
+            Some(read_varint_core(&mut r)? as u32)
+        } else {
+            None
+        };
+
+        let block_offset = if validation_status.contains(BlockStatus::BLOCK_HAVE_DATA) {
+            Some(read_varint_core(&mut r)?)
+        } else {
+            None
+        };
+
+        let undo_offset = if validation_status.contains(BlockStatus::BLOCK_HAVE_UNDO) {
+            Some(read_varint_core(&mut r)?)
+        } else {
+            None
+        };
+
+        let header = Header::consensus_decode(&mut r)?;
+
+        // we've read all the data
+        if r.position() != value.len() as u64 {
+            return Err(ScannerError::CorruptRecord(format!(
+                "{} trailing bytes in block index record",
+                value.len() as u64 - r.position()
+            )));
+        }
+
+        Ok(BlockIndexRecord {
+            header,
+            height,
+            num_transactions,
+            validation_status,
+            file,
+            block_offset,
+            undo_offset,
+        })
+    }
+
+    pub fn validation_status(&self) -> BlockStatus {
+        self.validation_status
+    }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct TransactionIndexRecord {
-    block_file_number: u32,
-    block_offset: u64,
-    tx_offset: u64,
+/// Statistics of one blk/rev file pair, from an `f` record of the block index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInformationRecord {
+    pub file: u32,
+    pub num_blocks: u32,
+    pub block_file_size: u64,
+    pub undo_file_size: u64,
+    pub lowest_height: u32,
+    pub highest_height: u32,
+    pub lowest_timestamp: u32,
+    pub highest_timestamp: u32,
+}
+
+impl FileInformationRecord {
+    fn parse(file: u32, value: &[u8]) -> Result<Self, ScannerError> {
+        let mut r = value;
+        let mut next = || {
+            read_varint_core(&mut r).map_err(|_| {
+                ScannerError::CorruptRecord(format!("truncated file information of {}", file))
+            })
+        };
+
+        Ok(Self {
+            file,
+            num_blocks: next()? as u32,
+            block_file_size: next()?,
+            undo_file_size: next()?,
+            lowest_height: next()? as u32,
+            highest_height: next()? as u32,
+            lowest_timestamp: next()? as u32,
+            highest_timestamp: next()? as u32,
+        })
+    }
+
+    /// Whether blocks of `height` may be stored in this file. Files overlap as
+    /// blocks are stored in the order they were downloaded.
+    pub fn contains_height(&self, height: u32) -> bool {
+        self.num_blocks > 0 && (self.lowest_height..=self.highest_height).contains(&height)
+    }
 }
 
 pub struct Scanner {
     block_index: rusty_leveldb::DB,
     block_obfs: Option<Vec<u8>>,
-    chain_state: rusty_leveldb::DB,
+    chain_state: Option<rusty_leveldb::DB>,
     chain_obfs: Option<Vec<u8>>,
-    datadir: PathBuf,
-    #[allow(dead_code)]
+    pub(crate) datadir: PathBuf,
     last_file_number: u32,
     genesis: bitcoin::Block,
     pub genesis_hash: bitcoin::BlockHash,
     pub tip_hash: bitcoin::BlockHash,
+    active_chain: Option<ActiveChain>,
+    network: Network,
+    magic: [u8; 4],
+    xor_key: XorKey,
+    pub(crate) block_files: Arc<BlockFileStore>,
+    pub(crate) verify_undo: bool,
+    pub(crate) txindex: Option<IndexDb>,
+    pub(crate) filter_index: Option<IndexDb>,
+    pub(crate) coinstats_index: Option<IndexDb>,
+    /// Height above which blocks are not read, see [`Scanner::new_snapshot`].
+    read_cap: Option<u32>,
+    /// Keeps the copied databases alive, must be dropped last.
+    pub(crate) copy: Option<CopyDir>,
 }
 
 bitflags::bitflags! {
@@ -79,69 +196,151 @@ bitflags::bitflags! {
     }
 }
 
-impl Scanner {
-    pub fn new(datadir: PathBuf) -> Self {
-        let mut block_index = datadir.clone();
-        let mut chain_state = datadir.clone();
-        block_index.push("blocks");
-        block_index.push("index");
-        chain_state.push("chainstate");
+impl BlockStatus {
+    /// Mirrors `CBlockIndex::IsValid`: the validity bits are a level, not a set
+    /// of flags, so `level` should be one of the `BLOCK_VALID_*` constants.
+    pub fn is_valid(self, level: BlockStatus) -> bool {
+        !self.intersects(Self::BLOCK_FAILED_MASK)
+            && (self & Self::BLOCK_VALID_MASK).bits() >= level.bits()
+    }
+}
 
-        let options = Options {
-            create_if_missing: false,
-            ..Default::default()
-        };
+impl Scanner {
+    /// Opens the datadir of a node running on `network`, refusing datadirs
+    /// whose genesis block belongs to another chain.
+    ///
+    /// `bitcoin` 0.30 has no network for testnet4, open testnet4 datadirs
+    /// with [`Network::Testnet`]. They are recognized by their block files'
+    /// [`TESTNET4_MAGIC`](crate::TESTNET4_MAGIC), see [`Scanner::magic`].
+    pub fn new(datadir: PathBuf, network: Network) -> Result<Self, ScannerError> {
+        Self::open(datadir, network, None, true)
+    }
 
-        let mut block_db = match DB::open(&block_index, options.clone()) {
-            Ok(db) => db,
-            Err(e) => match e.code {
-                rusty_leveldb::StatusCode::LockError => panic!("Please close bitcoin core first"),
-                _ => panic!("Error opening database: {:?}", e),
-            },
-        };
+    /// Opens the datadir of a running node by reading copies of its block
+    /// index and chainstate, taken in a temporary directory inside the
+    /// datadir that is removed when the scanner is dropped. Optional indexes
+    /// are copied on first use.
+    ///
+    /// Blocks above the copied chainstate tip may still be in flight and
+    /// can't be read, they fail with [`ScannerError::AboveSnapshotTip`].
+    ///
+    /// The copies are named `bitcoin-scanner-<pid>-<nanos>`. If the scanner
+    /// is killed before it is dropped, its copy stays and keeps tables
+    /// bitcoind has compacted away from being freed. On Linux, the copies of
+    /// processes that are gone are removed when the next snapshot is opened,
+    /// elsewhere remove them by hand while no scanner is running.
+    pub fn new_snapshot(datadir: PathBuf, network: Network) -> Result<Self, ScannerError> {
+        let copy_dir = datadir.clone();
+        Self::new_snapshot_in(datadir, network, &copy_dir)
+    }
 
-        let obfuscate_key = b"\x0e\x00obfuscate_key";
+    /// Like [`Scanner::new_snapshot`], but takes the copies in a temporary
+    /// directory inside `copy_dir`. The database tables are hard-linked, so
+    /// `copy_dir` must be on the same file system as the datadir, otherwise
+    /// opening fails with [`ScannerError::SnapshotLink`].
+    pub fn new_snapshot_in(
+        datadir: PathBuf,
+        network: Network,
+        copy_dir: &Path,
+    ) -> Result<Self, ScannerError> {
+        let copy = CopyDir::new(&datadir, copy_dir)?;
+        let mut scanner = Self::open(datadir, network, Some(copy), true)?;
+        scanner.cap_reads_at_tip()?;
+        Ok(scanner)
+    }
 
-        let block_obfs = block_db.get(obfuscate_key).map(|value| value[1..].to_vec());
+    /// Opens only the block index, for reading blocks. The tip is the best
+    /// block bitcoind flushed to the block index, the chainstate is opened
+    /// on first use. With `copy_dir` the block index is copied like for
+    /// [`Scanner::new_snapshot_in`].
+    pub(crate) fn open_block_index(
+        datadir: PathBuf,
+        network: Network,
+        copy_dir: Option<&Path>,
+    ) -> Result<Self, ScannerError> {
+        let copy = match copy_dir {
+            Some(copy_dir) => Some(CopyDir::new(&datadir, copy_dir)?),
+            None => None,
+        };
+        let snapshot = copy.is_some();
+        let mut scanner = Self::open(datadir, network, copy, false)?;
+        scanner.tip_hash = scanner.best_validated_block()?;
+        if snapshot {
+            scanner.cap_reads_at_tip()?;
+        }
+        Ok(scanner)
+    }
 
-        // let obfuscate_value = block_db
-        //     .get(obfuscate_key)
-        //     .expect("Failed to read obfuscation key");
+    /// Blocks above the tip may not be completely written yet.
+    fn cap_reads_at_tip(&mut self) -> Result<(), ScannerError> {
+        let tip_hash = self.tip_hash;
+        self.read_cap = Some(self.block_index_record(&tip_hash)?.height);
+        Ok(())
+    }
 
-        // dbg!("If this hangs, please run bitcoind --reindex-chainstate");
-        let mut chain_db = match DB::open(&chain_state, options) {
-            Ok(db) => db,
-            Err(e) => {
-                let code = e.code;
-                match code {
-                    rusty_leveldb::StatusCode::LockError => {
-                        panic!("Please close bitcoin core first")
-                    }
-                    _ => panic!(
-                        "Error opening chain state database: code {:?}, message {:?}",
-                        code, e.err
-                    ),
-                }
+    fn open(
+        datadir: PathBuf,
+        network: Network,
+        copy: Option<CopyDir>,
+        with_chain_state: bool,
+    ) -> Result<Self, ScannerError> {
+        // bitcoind flushes the block index before the chainstate, so the
+        // block index copied last knows every block the chainstate does
+        let (mut chain_db, chain_obfs) = match with_chain_state {
+            true => {
+                let (db, obfs) = Self::open_chain_state(&datadir, copy.as_ref())?;
+                (Some(db), obfs)
             }
+            false => (None, None),
         };
 
-        let obfuscate_key = b"\x0e\x00obfuscate_key";
-        let chain_obfs = chain_db.get(obfuscate_key).map(|value| value[1..].to_vec());
-
-        let tip = chain_db.get(b"B").unwrap();
-        let tip = Self::obfs(&chain_obfs, &tip);
-        let tip_hash: bitcoin::BlockHash = Hash::from_slice(&tip).unwrap();
+        let mut block_index = datadir.join("blocks").join("index");
+        if let Some(copy) = &copy {
+            block_index = copy.copy_db(&block_index)?;
+        }
+        let (mut block_db, block_obfs) = Self::open_db(&block_index)?;
 
         // get the last file number
         let key = b"l";
-        let value = block_db.get(key).unwrap();
-        let value = u32::from_le_bytes(value[..4].try_into().unwrap());
-        let last_file_number = value;
-
-        let genesis = Self::read_genesis(datadir.clone());
+        let value = block_db
+            .get(key)
+            .ok_or_else(|| ScannerError::CorruptRecord("block index has no last file".into()))?;
+        let value = value
+            .get(..4)
+            .ok_or_else(|| ScannerError::CorruptRecord("invalid last file number".into()))?;
+        let last_file_number = u32::from_le_bytes(value.try_into().unwrap());
+
+        let xor_key = XorKey::read(&datadir.join("blocks"))?;
+        let (magic, genesis) = match Self::read_genesis(&datadir, network, xor_key) {
+            Ok(chain) => chain,
+            // without blk00000.dat, the block index must know the network's genesis
+            Err(ScannerError::PrunedFile(_)) => chains(network)
+                .into_iter()
+                .find(|(_, genesis)| {
+                    let key = [&b"b"[..], &genesis.block_hash().to_byte_array()].concat();
+                    block_db.get(&key).is_some()
+                })
+                .ok_or(ScannerError::WrongNetwork {
+                    expected: network,
+                    found: None,
+                })?,
+            Err(e) => return Err(e),
+        };
         let genesis_hash = genesis.block_hash();
 
-        Self {
+        let mut tip_hash = genesis_hash;
+        if let Some(chain_db) = chain_db.as_mut() {
+            let tip = chain_db.get(b"B").ok_or_else(|| {
+                ScannerError::CorruptRecord("chainstate has no best block".into())
+            })?;
+            let tip = Self::obfs(&chain_obfs, &tip);
+            tip_hash = Hash::from_slice(&tip)
+                .map_err(|_| ScannerError::CorruptRecord("invalid chainstate best block".into()))?;
+        }
+
+        let block_files = Arc::new(BlockFileStore::new(datadir.join("blocks"), magic, xor_key));
+
+        Ok(Self {
             datadir,
             block_index: block_db,
             chain_state: chain_db,
@@ -151,40 +350,121 @@ impl Scanner {
             tip_hash,
             chain_obfs,
             block_obfs,
+            active_chain: None,
+            network,
+            magic,
+            xor_key,
+            block_files,
+            verify_undo: false,
+            txindex: None,
+            filter_index: None,
+            coinstats_index: None,
+            read_cap: None,
+            copy,
+        })
+    }
+
+    /// Opens the chainstate, or a copy of it in `copy`.
+    fn open_chain_state(
+        datadir: &Path,
+        copy: Option<&CopyDir>,
+    ) -> Result<(DB, Option<Vec<u8>>), ScannerError> {
+        let path = datadir.join("chainstate");
+        match copy {
+            Some(copy) => copy.copy_chain_state(&path),
+            None => Self::open_db(&path),
         }
     }
 
-    pub fn scan_blocks_db<F>(&mut self, mut f: F)
+    /// The chainstate, opened on first use if the scanner was opened without it.
+    fn chain_state(&mut self) -> Result<&mut DB, ScannerError> {
+        if self.chain_state.is_none() {
+            let (db, obfs) = Self::open_chain_state(&self.datadir, self.copy.as_ref())?;
+            self.chain_state = Some(db);
+            self.chain_obfs = obfs;
+        }
+        Ok(self.chain_state.as_mut().unwrap())
+    }
+
+    /// Opens an existing LevelDB and reads its obfuscation key, if any.
+    pub(crate) fn open_db(path: &Path) -> Result<(DB, Option<Vec<u8>>), ScannerError> {
+        let options = Options {
+            create_if_missing: false,
+            ..Default::default()
+        };
+
+        let mut db = match DB::open(path, options) {
+            Ok(db) => db,
+            Err(e) => match e.code {
+                rusty_leveldb::StatusCode::LockError => {
+                    return Err(ScannerError::DatabaseLocked(path.to_owned()))
+                }
+                _ => return Err(ScannerError::Database(path.to_owned(), e)),
+            },
+        };
+
+        let obfuscate_key = b"\x0e\x00obfuscate_key";
+        let obfs = db.get(obfuscate_key).map(|value| value[1..].to_vec());
+
+        Ok((db, obfs))
+    }
+
+    pub fn scan_blocks_db<F>(&mut self, mut f: F) -> Result<(), ScannerError>
     where
         F: FnMut(Vec<u8>, Vec<u8>),
     {
-        let mut it = self.block_index.new_iter().unwrap();
+        let mut it = self
+            .block_index
+            .new_iter()
+            .map_err(|e| ScannerError::Database(self.datadir.join("blocks").join("index"), e))?;
 
         while let Some((key, value)) = it.next() {
             let value = Self::obfs(&self.block_obfs, &value);
             f(key, value);
         }
+        Ok(())
     }
 
-    pub fn scan_chain_db<F>(&mut self, mut f: F)
+    pub fn scan_chain_db<F>(&mut self, mut f: F) -> Result<(), ScannerError>
     where
         F: FnMut(Vec<u8>, Vec<u8>),
     {
-        let mut it = self.chain_state.new_iter().unwrap();
+        let mut it = self
+            .chain_state()?
+            .new_iter()
+            .map_err(|e| ScannerError::Database(self.datadir.join("chainstate"), e))?;
 
         while let Some((key, value)) = it.next() {
             let value = Self::obfs(&self.chain_obfs, &value);
             f(key, value);
         }
+        Ok(())
+    }
+
+    /// Iterates every unspent output in the chainstate, in key order.
+    pub fn utxos(&mut self) -> Result<Utxos, ScannerError> {
+        let it = self
+            .chain_state()?
+            .new_iter()
+            .map_err(|e| ScannerError::Database(self.datadir.join("chainstate"), e))?;
+        Ok(Utxos::new(it, self.chain_obfs.clone()))
+    }
+
+    /// Looks up a single unspent output, `None` if it is spent or never existed.
+    pub fn get_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>, ScannerError> {
+        let key = coin_key(outpoint);
+        match self.chain_get(&key)? {
+            Some(value) => Coin::parse(&key, &value).map(Some),
+            None => Ok(None),
+        }
     }
 
-    #[allow(dead_code)]
-    fn chain_get(&mut self, key: &[u8]) -> Vec<u8> {
-        let value = self.chain_state.get(key).unwrap();
-        Self::obfs(&self.chain_obfs, &value)
+    fn chain_get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, ScannerError> {
+        let value = self.chain_state()?.get(key);
+        Ok(value.map(|value| Self::obfs(&self.chain_obfs, &value)))
     }
 
-    fn obfs(obfs: &Option<Vec<u8>>, value: &[u8]) -> Vec<u8> {
+    pub(crate) fn obfs(obfs: &Option<Vec<u8>>, value: &[u8]) -> Vec<u8> {
         match obfs {
             Some(obfs) => value
                 .iter()
@@ -195,125 +475,466 @@ impl Scanner {
         }
     }
 
-    pub fn block_index_record(&mut self, key: &bitcoin::BlockHash) -> BlockIndexRecord {
+    pub fn block_index_record(
+        &mut self,
+        key: &bitcoin::BlockHash,
+    ) -> Result<BlockIndexRecord, ScannerError> {
         let key1 = b"b";
         let key2 = &key.as_raw_hash().to_byte_array()[..];
-        let key = [key1, key2].concat();
-        let value = self.block_index.get(&key).unwrap();
+        let db_key = [key1, key2].concat();
+        let value = self
+            .block_index
+            .get(&db_key)
+            .ok_or(ScannerError::UnknownBlock(*key))?;
+        let value = Self::obfs(&self.block_obfs, &value);
+
+        BlockIndexRecord::parse(&value)
+    }
 
-        let mut r = Cursor::new(value);
+    /// Reads the `f` record of every blk/rev file, up to the last one in use.
+    pub fn block_files(&mut self) -> Result<Vec<FileInformationRecord>, ScannerError> {
+        (0..=self.last_file_number)
+            .map(|file| {
+                let key = [&b"f"[..], &file.to_le_bytes()[..]].concat();
+                let value = self.block_index.get(&key).ok_or_else(|| {
+                    ScannerError::CorruptRecord(format!("no file information for {}", file))
+                })?;
+                let value = Self::obfs(&self.block_obfs, &value);
+                FileInformationRecord::parse(file, &value)
+            })
+            .collect()
+    }
 
-        let record_version = read_varint_core(&mut r).unwrap() as u32;
-        // test the code base with earlier versions and see if it works
-        // please PR if it does
-        more_asserts::assert_ge!(record_version, 220000);
-        let height = read_varint_core(&mut r).unwrap() as u32;
-        let validation_status = read_varint_core(&mut r).unwrap() as u32;
-        let validation_status = BlockStatus::from_bits_truncate(validation_status);
-        let num_transactions = read_varint_core(&mut r).unwrap() as u32;
+    /// Calls `f` with every `b` record in the block index, in key order.
+    fn scan_block_index_records<F>(&mut self, mut f: F) -> Result<(), ScannerError>
+    where
+        F: FnMut(bitcoin::BlockHash, BlockIndexRecord),
+    {
+        let mut it = self
+            .block_index
+            .new_iter()
+            .map_err(|e| ScannerError::Database(self.datadir.join("blocks").join("index"), e))?;
+
+        let (mut key, mut value) = (vec![], vec![]);
+        it.seek(b"b");
+        while it.valid() {
+            it.current(&mut key, &mut value);
+            if key.first() != Some(&b'b') {
+                break;
+            }
+            let hash = Hash::from_slice(&key[1..])
+                .map_err(|_| ScannerError::CorruptRecord("invalid block index key".into()))?;
+            let value = Self::obfs(&self.block_obfs, &value);
+            f(hash, BlockIndexRecord::parse(&value)?);
+            it.advance();
+        }
+        Ok(())
+    }
 
-        let file = if validation_status.contains(BlockStatus::BLOCK_HAVE_DATA)
-            || validation_status.contains(BlockStatus::BLOCK_HAVE_UNDO)
-        {
-            //dbg!(validation_status);
-            Some(read_varint_core(&mut r).unwrap() as u32)
-        } else {
-            None
-        };
+    /// Returns the best chain ending at `tip_hash`, built from the block index
+    /// on first use and cached afterwards.
+    pub fn active_chain(&mut self) -> Result<&ActiveChain, ScannerError> {
+        if self.active_chain.is_none() {
+            let chain = self.build_active_chain()?;
+            self.active_chain = Some(chain);
+        }
+        Ok(self.active_chain.as_ref().unwrap())
+    }
 
-        let block_offset = if validation_status.contains(BlockStatus::BLOCK_HAVE_DATA) {
-            Some(read_varint_core(&mut r).unwrap())
-        } else {
-            None
-        };
+    pub fn hash_at_height(
+        &mut self,
+        height: u32,
+    ) -> Result<Option<bitcoin::BlockHash>, ScannerError> {
+        Ok(self.active_chain()?.hash_at_height(height))
+    }
 
-        let undo_offset = if validation_status.contains(BlockStatus::BLOCK_HAVE_UNDO) {
-            Some(read_varint_core(&mut r).unwrap())
-        } else {
-            None
-        };
+    pub fn height_of(&mut self, hash: &bitcoin::BlockHash) -> Result<Option<u32>, ScannerError> {
+        Ok(self.active_chain()?.height_of(hash))
+    }
 
-        let header = Header::consensus_decode(&mut r).unwrap();
+    /// Lists every chain tip in the block index like `getchaintips`, highest first.
+    pub fn chain_tips(&mut self) -> Result<Vec<ChainTip>, ScannerError> {
+        let mut entries = HashMap::new();
+        self.scan_block_index_records(|hash, record| {
+            entries.insert(
+                hash,
+                (
+                    record.height,
+                    record.header.prev_blockhash,
+                    record.validation_status,
+                    record.num_transactions,
+                ),
+            );
+        })?;
+        let parents: HashSet<_> = entries.values().map(|(_, prev, _, _)| *prev).collect();
+
+        let chain = self.active_chain()?;
+        let mut tips = vec![];
+        for (hash, (height, _, status, _)) in &entries {
+            if parents.contains(hash) && *hash != chain.tip() {
+                continue;
+            }
 
-        // we've read all the data
-        assert!(r.position() == r.get_ref().len() as u64);
+            // walk back to the fork point, noting whether every block on the
+            // branch has ever had its transactions
+            let mut fork = *hash;
+            let mut have_txs = true;
+            while !chain.contains(&fork) {
+                let (_, prev, _, num_transactions) = entries.get(&fork).ok_or_else(|| {
+                    ScannerError::CorruptRecord(format!(
+                        "ancestor {} of tip {} missing",
+                        fork, hash
+                    ))
+                })?;
+                have_txs &= *num_transactions > 0;
+                fork = *prev;
+            }
+            let fork_height = chain.height_of(&fork).unwrap();
+
+            let status = if chain.contains(hash) {
+                ChainTipStatus::Active
+            } else if status.intersects(BlockStatus::BLOCK_FAILED_MASK) {
+                ChainTipStatus::Invalid
+            } else if !have_txs {
+                ChainTipStatus::HeadersOnly
+            } else if status.is_valid(BlockStatus::BLOCK_VALID_SCRIPTS) {
+                ChainTipStatus::ValidFork
+            } else if status.is_valid(BlockStatus::BLOCK_VALID_TREE) {
+                ChainTipStatus::ValidHeaders
+            } else {
+                ChainTipStatus::Unknown
+            };
+
+            tips.push(ChainTip {
+                hash: *hash,
+                height: *height,
+                branch_len: height - fork_height,
+                status,
+            });
+        }
 
-        BlockIndexRecord {
-            header,
-            height,
-            num_transactions,
-            validation_status,
-            file,
-            block_offset,
-            undo_offset,
+        tips.sort_by(|a, b| b.height.cmp(&a.height).then(a.hash.cmp(&b.hash)));
+        Ok(tips)
+    }
+
+    /// Iterates the blocks of the branch ending at `tip` that are not part of
+    /// the active chain, oldest first. Blocks we only have headers for yield
+    /// [`ScannerError::MissingBlockData`].
+    pub fn stale_blocks(&mut self, tip: &bitcoin::BlockHash) -> Result<Blocks<'_>, ScannerError> {
+        let mut hashes = vec![];
+        let mut hash = *tip;
+        while !self.active_chain()?.contains(&hash) {
+            hashes.push(hash);
+            hash = self.block_index_record(&hash)?.header.prev_blockhash;
+        }
+        hashes.reverse();
+
+        Ok(Blocks::new(self, hashes))
+    }
+
+    /// The heights of the active chain whose block and undo data are still
+    /// on disk, from the lowest block not pruned up to the tip. Genesis never
+    /// has undo data and counts as available when its block is. `None` if
+    /// even the tip has been pruned.
+    ///
+    /// This scans the whole block index.
+    pub fn available_range(&mut self) -> Result<Option<RangeInclusive<u32>>, ScannerError> {
+        let mut pruned = HashSet::new();
+        self.scan_block_index_records(|hash, record| {
+            let status = record.validation_status();
+            let have = match record.height {
+                0 => BlockStatus::BLOCK_HAVE_DATA,
+                _ => BlockStatus::BLOCK_HAVE_MASK,
+            };
+            if !status.contains(have) {
+                pruned.insert(hash);
+            }
+        })?;
+
+        let chain = self.active_chain()?;
+        let mut lowest = None;
+        for height in (0..=chain.tip_height()).rev() {
+            if pruned.contains(&chain.hash_at_height(height).unwrap()) {
+                break;
+            }
+            lowest = Some(height);
         }
+        Ok(lowest.map(|lowest| lowest..=chain.tip_height()))
     }
 
-    fn read_genesis(datadir: PathBuf) -> bitcoin::Block {
-        let file = datadir.join("blocks").join("blk00000.dat");
-        let mut file = std::fs::File::open(file)
-            .expect("First blk file not found, do you have pruning enabled?");
+    /// The block with the most work whose scripts were validated, the tip
+    /// as of bitcoind's last flush of the block index.
+    fn best_validated_block(&mut self) -> Result<bitcoin::BlockHash, ScannerError> {
+        let mut entries = vec![];
+        self.scan_block_index_records(|hash, record| {
+            entries.push((
+                record.height,
+                hash,
+                record.header,
+                record.validation_status(),
+            ));
+        })?;
+        entries.sort_by_key(|(height, ..)| *height);
+
+        let mut chain_work: HashMap<bitcoin::BlockHash, Work> = HashMap::new();
+        let mut best: Option<(Work, bitcoin::BlockHash)> = None;
+        for (height, hash, header, status) in entries {
+            let work = match chain_work.get(&header.prev_blockhash) {
+                Some(work) => *work + header.work(),
+                None if height == 0 => header.work(),
+                None => continue,
+            };
+            chain_work.insert(hash, work);
+            // genesis is never script-validated, it is connected without that
+            let validated = height == 0 || status.is_valid(BlockStatus::BLOCK_VALID_SCRIPTS);
+            let better = match best {
+                Some((best_work, _)) => work > best_work,
+                None => true,
+            };
+            if validated && better {
+                best = Some((work, hash));
+            }
+        }
+        best.map(|(_, hash)| hash)
+            .ok_or_else(|| ScannerError::CorruptRecord("block index has no genesis".into()))
+    }
+
+    fn build_active_chain(&mut self) -> Result<ActiveChain, ScannerError> {
+        let mut links = HashMap::new();
+        self.scan_block_index_records(|hash, record| {
+            links.insert(hash, (record.height, record.header.prev_blockhash));
+        })?;
+
+        let tip_height = match links.get(&self.tip_hash) {
+            Some((height, _)) => *height,
+            None => return Err(ScannerError::UnknownBlock(self.tip_hash)),
+        };
+
+        // walk back from the tip, every ancestor must be in the index
+        let mut hashes = vec![self.tip_hash; tip_height as usize + 1];
+        let mut hash = self.tip_hash;
+        for height in (0..=tip_height).rev() {
+            let (record_height, prev_hash) = links.get(&hash).ok_or_else(|| {
+                ScannerError::CorruptRecord(format!("ancestor {} of tip missing", hash))
+            })?;
+            if *record_height != height {
+                return Err(ScannerError::CorruptRecord(format!(
+                    "block {} has height {}, expected {}",
+                    hash, record_height, height
+                )));
+            }
+            hashes[height as usize] = hash;
+            hash = *prev_hash;
+        }
+
+        Ok(ActiveChain::new(hashes))
+    }
+
+    /// Reads the first block of blk00000.dat and checks that it is the
+    /// genesis block of `network`, stored under the network's magic.
+    /// Returns the magic and genesis block, testnet4's for a
+    /// [`Network::Testnet`] datadir with testnet4 block files.
+    fn read_genesis(
+        datadir: &Path,
+        network: Network,
+        xor_key: XorKey,
+    ) -> Result<([u8; 4], bitcoin::Block), ScannerError> {
+        let path = datadir.join("blocks").join("blk00000.dat");
+        let mut file = XorReader::new(Self::open_file(&path)?, xor_key);
         let mut magic_size = [0; 8];
-        // todo check magic
-        file.read_exact(&mut magic_size).unwrap();
-        let size = magic_size[4..8].try_into().unwrap();
-        let _size = u32::from_le_bytes(size);
+        file.read_exact(&mut magic_size)?;
+        let magic: [u8; 4] = magic_size[..4].try_into().unwrap();
+        let expected_genesis = match chains(network).into_iter().find(|(m, _)| *m == magic) {
+            Some((_, genesis)) => genesis.block_hash(),
+            None => {
+                return Err(ScannerError::WrongNetwork {
+                    expected: network,
+                    found: Network::from_magic(Magic::from_bytes(magic)),
+                })
+            }
+        };
+        let size = u32::from_le_bytes(magic_size[4..8].try_into().unwrap()) as u64;
+        if size > MAX_SIZE {
+            return Err(ScannerError::CorruptRecord(format!(
+                "{} has record size {}",
+                path.display(),
+                size
+            )));
+        }
+        let mut buf = vec![0; size as usize];
+        file.read_exact(&mut buf)?;
+
+        let genesis: bitcoin::Block = deserialize(&buf)?;
+        if genesis.block_hash() != expected_genesis {
+            return Err(ScannerError::WrongNetwork {
+                expected: network,
+                found: None,
+            });
+        }
+        Ok((magic, genesis))
+    }
 
-        bitcoin::Block::consensus_decode(&mut file).unwrap()
+    /// Opens a blk/rev file, reporting a missing file as pruned.
+    pub(crate) fn open_file(path: &Path) -> Result<BufReader<File>, ScannerError> {
+        match File::open(path) {
+            Ok(f) => Ok(BufReader::new(f)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(ScannerError::PrunedFile(path.to_owned()))
+            }
+            Err(e) => Err(e.into()),
+        }
     }
 
-    pub fn read_block(&mut self, id: &bitcoin::BlockHash) -> bitcoin::Block {
-        let record = self.block_index_record(id);
+    pub fn read_block(&mut self, id: &bitcoin::BlockHash) -> Result<bitcoin::Block, ScannerError> {
+        let record = self.block_index_record(id)?;
         self.read_block_from_record(&record)
     }
 
-    pub fn read_block_from_record(&mut self, record: &BlockIndexRecord) -> bitcoin::Block {
-        let file = self
-            .datadir
-            .clone()
-            .join("blocks")
-            .join(format!("blk{:05}.dat", record.file.unwrap()));
+    pub fn read_block_from_record(
+        &mut self,
+        record: &BlockIndexRecord,
+    ) -> Result<bitcoin::Block, ScannerError> {
+        let buf = self.read_raw_block_from_record(record)?;
 
-        let f = std::fs::File::open(file).unwrap();
-        let mut file = BufReader::new(f);
-        let mut magic_size = [0; 8];
-        // todo check magic
+        // fails unless the block spans exactly the record size
+        Ok(deserialize(&buf)?)
+    }
+
+    /// Reads the serialized block, to decode it selectively with a
+    /// [`BlockView`](crate::BlockView).
+    pub fn read_raw_block(&mut self, id: &bitcoin::BlockHash) -> Result<RecordBytes, ScannerError> {
+        let record = self.block_index_record(id)?;
+        self.read_raw_block_from_record(&record)
+    }
+
+    pub fn read_raw_block_from_record(
+        &mut self,
+        record: &BlockIndexRecord,
+    ) -> Result<RecordBytes, ScannerError> {
+        let (file, block_offset, _) = self.record_locations(record)?;
+        self.block_files.block_record(file, block_offset)
+    }
+
+    fn check_read_cap(&self, record: &BlockIndexRecord) -> Result<(), ScannerError> {
+        match self.read_cap {
+            Some(cap) if record.height > cap => {
+                Err(ScannerError::AboveSnapshotTip(record.header.block_hash()))
+            }
+            _ => Ok(()),
+        }
+    }
 
-        file.seek(SeekFrom::Start(record.block_offset.unwrap() - 8))
-            .unwrap();
+    /// Enables checking the double-SHA256 checksum of every undo record read.
+    pub fn verify_undo_checksums(&mut self, verify: bool) {
+        self.verify_undo = verify;
+    }
 
-        file.read_exact(&mut magic_size).unwrap();
-        let size = magic_size[4..8].try_into().unwrap();
-        let _size = u32::from_le_bytes(size);
+    pub fn read_undo(&mut self, id: &bitcoin::BlockHash) -> Result<BlockUndo, ScannerError> {
+        let block_index_record = self.block_index_record(id)?;
+        self.read_undo_from_record(&block_index_record)
+    }
 
-        bitcoin::Block::consensus_decode(&mut file).unwrap()
+    pub fn read_undo_from_record(
+        &mut self,
+        record: &BlockIndexRecord,
+    ) -> Result<BlockUndo, ScannerError> {
+        self.check_read_cap(record)?;
+        let (file, undo_offset) = match (record.file, record.undo_offset) {
+            (Some(file), Some(offset)) => (file, offset),
+            _ => return Err(ScannerError::MissingUndoData(record.header.block_hash())),
+        };
+        let buf = self.block_files.undo_record(file, undo_offset)?;
+        parse_undo_record(&buf, record, self.verify_undo)
     }
 
-    pub fn read_undo(&mut self, id: &bitcoin::BlockHash) -> BlockUndo {
-        let block_index_record = self.block_index_record(id);
+    /// Iterates the active chain in ascending height order over `range`.
+    ///
+    /// Heights past the tip are ignored. Blocks without undo data, like
+    /// genesis, come with `None`.
+    pub fn blocks<R: RangeBounds<u32>>(&mut self, range: R) -> Result<Blocks<'_>, ScannerError> {
+        let hashes = self.active_chain_range(range)?;
+        Ok(Blocks::new(self, hashes))
+    }
+
+    /// Hashes of the active chain in `range`, heights past the tip are ignored.
+    pub(crate) fn active_chain_range<R: RangeBounds<u32>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<bitcoin::BlockHash>, ScannerError> {
+        let chain = self.active_chain()?;
+        let start = match range.start_bound() {
+            Bound::Included(&h) => h as usize,
+            Bound::Excluded(&h) => h as usize + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&h) => h as usize + 1,
+            Bound::Excluded(&h) => h as usize,
+            Bound::Unbounded => usize::MAX,
+        };
+        let hashes = chain.hashes();
+        let end = end.min(hashes.len());
+        Ok(hashes.get(start..end).unwrap_or_default().to_vec())
+    }
+
+    /// The blk/rev file number and offsets of a block's records, for reading
+    /// them without the scanner. The undo offset is `None` if there is no
+    /// undo data, like for genesis.
+    pub(crate) fn record_locations(
+        &self,
+        record: &BlockIndexRecord,
+    ) -> Result<(u32, u64, Option<u64>), ScannerError> {
+        self.check_read_cap(record)?;
+        match (record.file, record.block_offset) {
+            (Some(file), Some(offset)) => Ok((file, offset, record.undo_offset)),
+            _ => Err(ScannerError::MissingBlockData(record.header.block_hash())),
+        }
+    }
 
-        // dbg!(&block_index_record);
+    /// Reads the index record, block and (if present) undo data of a block.
+    pub fn read_block_with_undo(
+        &mut self,
+        id: &bitcoin::BlockHash,
+    ) -> Result<(BlockIndexRecord, bitcoin::Block, Option<BlockUndo>), ScannerError> {
+        let record = self.block_index_record(id)?;
+        let block = self.read_block_from_record(&record)?;
+        let undo = match record.undo_offset {
+            Some(_) => Some(self.read_undo_from_record(&record)?),
+            None => None,
+        };
+        Ok((record, block, undo))
+    }
 
-        let file = self
-            .datadir
-            .clone()
-            .join("blocks")
-            .join(format!("rev{:05}.dat", block_index_record.file.unwrap()));
+    /// Like [`Scanner::read_block_with_undo`], also reading blocks above the
+    /// snapshot tip. Only for blocks known to be completely written, like
+    /// ones that were part of the active chain.
+    pub(crate) fn read_block_with_undo_uncapped(
+        &mut self,
+        id: &bitcoin::BlockHash,
+    ) -> Result<(BlockIndexRecord, bitcoin::Block, Option<BlockUndo>), ScannerError> {
+        let read_cap = self.read_cap.take();
+        let result = self.read_block_with_undo(id);
+        self.read_cap = read_cap;
+        result
+    }
 
-        let f = std::fs::File::open(file).unwrap();
-        let mut file = BufReader::new(f);
+    pub fn network(&self) -> Network {
+        self.network
+    }
 
-        // dbg!(block_index_record.undo_offset);
+    /// The memory-mapped blk/rev files, shareable with other threads.
+    pub fn block_file_store(&self) -> Arc<BlockFileStore> {
+        self.block_files.clone()
+    }
 
-        file.seek(SeekFrom::Start(block_index_record.undo_offset.unwrap() - 8))
-            .unwrap();
-        let mut magic_size = [0; 8];
-        file.read_exact(&mut magic_size).unwrap();
-        let size = magic_size[4..8].try_into().unwrap();
-        let _size = u32::from_le_bytes(size);
+    /// The key blk/rev files are obfuscated with, all zeros before Bitcoin Core 28.
+    pub fn xor_key(&self) -> XorKey {
+        self.xor_key
+    }
 
-        BlockUndo::parse(&mut file, Some(block_index_record.num_transactions)).unwrap()
-        //let undo = (&mut file).unwrap();
+    /// The magic every blk/rev record of this datadir starts with.
+    pub fn magic(&self) -> [u8; 4] {
+        self.magic
     }
 
     pub fn genesis(&self) -> bitcoin::Block {
@@ -324,3 +945,29 @@ impl Scanner {
         self.genesis_hash
     }
 }
+
+/// Decodes an undo record read with its trailing checksum, optionally
+/// verifying the checksum first.
+pub(crate) fn parse_undo_record(
+    buf: &[u8],
+    record: &BlockIndexRecord,
+    verify: bool,
+) -> Result<BlockUndo, ScannerError> {
+    let (body, checksum) = buf.split_at(buf.len() - 32);
+    if verify && undo_checksum(&record.header.prev_blockhash, body)[..] != checksum[..] {
+        return Err(ScannerError::UndoChecksumMismatch(
+            record.header.block_hash(),
+        ));
+    }
+
+    let mut r = buf;
+    let undo = BlockUndo::parse(&mut r, Some(record.num_transactions))
+        .map_err(|e| ScannerError::Decode(e.into()))?;
+    if !r.is_empty() {
+        return Err(ScannerError::CorruptRecord(format!(
+            "undo of {} is shorter than its record",
+            record.header.block_hash()
+        )));
+    }
+    Ok(undo)
+}
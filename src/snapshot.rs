@@ -0,0 +1,313 @@
+use std::io::{self, Read, Write};
+
+use bitcoin::{hashes::Hash, BlockHash, OutPoint, Txid};
+
+use crate::{read_compact_size, write_compact_size, Coin, Scanner, ScannerError};
+
+/// Every snapshot starts with these bytes.
+const SNAPSHOT_MAGIC: [u8; 5] = *b"utxo\xff";
+const SNAPSHOT_VERSION: u16 = 2;
+
+/// The header of a `dumptxoutset` snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotMetadata {
+    /// Magic of the network the snapshot was taken on.
+    pub network_magic: [u8; 4],
+    /// The block the UTXO set belongs to.
+    pub base_blockhash: BlockHash,
+    pub coins_count: u64,
+}
+
+impl SnapshotMetadata {
+    pub fn parse<R: Read>(reader: &mut R) -> Result<Self, ScannerError> {
+        let mut magic = [0; 5];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(ScannerError::CorruptRecord(format!(
+                "not a utxo snapshot, magic {:02x?}",
+                magic
+            )));
+        }
+
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(ScannerError::CorruptRecord(format!(
+                "unsupported utxo snapshot version {}",
+                version
+            )));
+        }
+
+        let mut network_magic = [0; 4];
+        reader.read_exact(&mut network_magic)?;
+        let mut hash = [0; 32];
+        reader.read_exact(&mut hash)?;
+        let mut coins_count = [0; 8];
+        reader.read_exact(&mut coins_count)?;
+
+        Ok(Self {
+            network_magic,
+            base_blockhash: BlockHash::from_byte_array(hash),
+            coins_count: u64::from_le_bytes(coins_count),
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&self.network_magic)?;
+        writer.write_all(&self.base_blockhash.to_byte_array())?;
+        writer.write_all(&self.coins_count.to_le_bytes())
+    }
+}
+
+/// Reads the coins of a `dumptxoutset` snapshot.
+///
+/// Coins are grouped per txid: the txid, the number of coins, then each
+/// coin's vout and [`Coin`] serialization. To validate a snapshot against an
+/// assumeutxo hash, feed the coins to a
+/// [`UtxoSetHasher`](crate::UtxoSetHasher) of kind `HashSerialized`.
+pub struct SnapshotReader<R> {
+    reader: R,
+    metadata: SnapshotMetadata,
+    remaining: u64,
+    txid: Txid,
+    remaining_in_group: u64,
+    done: bool,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, ScannerError> {
+        let metadata = SnapshotMetadata::parse(&mut reader)?;
+        Ok(Self {
+            reader,
+            metadata,
+            remaining: metadata.coins_count,
+            txid: Txid::all_zeros(),
+            remaining_in_group: 0,
+            done: false,
+        })
+    }
+
+    pub fn metadata(&self) -> &SnapshotMetadata {
+        &self.metadata
+    }
+
+    fn read_coin(&mut self) -> Result<Coin, ScannerError> {
+        if self.remaining_in_group == 0 {
+            let mut txid = [0; 32];
+            self.reader.read_exact(&mut txid)?;
+            self.txid = Txid::from_byte_array(txid);
+            self.remaining_in_group = read_compact_size(&mut self.reader)?;
+            if self.remaining_in_group == 0 || self.remaining_in_group > self.remaining {
+                return Err(ScannerError::CorruptRecord(format!(
+                    "snapshot group of {} has {} coins, {} left",
+                    self.txid, self.remaining_in_group, self.remaining
+                )));
+            }
+        }
+
+        let vout = read_compact_size(&mut self.reader)?;
+        let vout = u32::try_from(vout).map_err(|_| {
+            ScannerError::CorruptRecord(format!("snapshot vout {} of {}", vout, self.txid))
+        })?;
+        let outpoint = OutPoint {
+            txid: self.txid,
+            vout,
+        };
+        let coin = Coin::deserialize(outpoint, &mut self.reader)?;
+
+        self.remaining_in_group -= 1;
+        self.remaining -= 1;
+        Ok(coin)
+    }
+
+    /// Errors if there is data after the last coin, like Bitcoin Core does.
+    fn check_end(&mut self) -> Result<(), ScannerError> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(()),
+            _ => Err(ScannerError::CorruptRecord(
+                "snapshot has more coins than its metadata says".to_owned(),
+            )),
+        }
+    }
+}
+
+impl<R: Read> Iterator for SnapshotReader<R> {
+    type Item = Result<Coin, ScannerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match self.remaining {
+            0 => match self.check_end() {
+                Ok(()) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e) => Err(e),
+            },
+            _ => self.read_coin(),
+        };
+        // stop after the first error, the position in the stream is lost
+        if result.is_err() {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
+
+impl Scanner {
+    /// Writes the chainstate as a `dumptxoutset` snapshot of the chainstate
+    /// tip, byte for byte what Bitcoin Core writes for the same UTXO set.
+    ///
+    /// The chainstate is read twice, first to count the coins for the header.
+    pub fn dump_txout_set<W: Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> Result<SnapshotMetadata, ScannerError> {
+        let mut coins_count = 0;
+        for coin in self.utxos()? {
+            coin?;
+            coins_count += 1;
+        }
+
+        let metadata = SnapshotMetadata {
+            network_magic: self.magic(),
+            base_blockhash: self.tip_hash,
+            coins_count,
+        };
+        metadata.serialize(writer)?;
+
+        // coins are in key order, so all outputs of a txid are adjacent
+        let mut group: Vec<Coin> = vec![];
+        let mut written = 0;
+        for coin in self.utxos()? {
+            let coin = coin?;
+            if matches!(group.first(), Some(first) if first.outpoint.txid != coin.outpoint.txid) {
+                write_group(writer, &group)?;
+                written += group.len() as u64;
+                group.clear();
+            }
+            group.push(coin);
+        }
+        write_group(writer, &group)?;
+        written += group.len() as u64;
+
+        if written != coins_count {
+            return Err(ScannerError::CorruptRecord(format!(
+                "chainstate changed while writing the snapshot, {} coins counted, {} written",
+                coins_count, written
+            )));
+        }
+        Ok(metadata)
+    }
+}
+
+fn write_group<W: Write>(writer: &mut W, group: &[Coin]) -> io::Result<()> {
+    let Some(first) = group.first() else {
+        return Ok(());
+    };
+    writer.write_all(&first.outpoint.txid.to_byte_array())?;
+    write_compact_size(writer, group.len() as u64)?;
+    for coin in group {
+        write_compact_size(writer, coin.outpoint.vout as u64)?;
+        coin.serialize(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    /// Coins of every script kind, three txids with several vouts each.
+    fn groups() -> Vec<Vec<Coin>> {
+        let scripts = crate::tests::scripts();
+        scripts
+            .chunks(3)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let txid = Txid::from_byte_array([i as u8 + 1; 32]);
+                chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(j, (script, _))| Coin {
+                        outpoint: OutPoint {
+                            txid,
+                            vout: j as u32 * 300,
+                        },
+                        height: 700_000 + i as u32,
+                        is_coinbase: j == 0,
+                        amount: 1000 * j as u64,
+                        script: script.clone(),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn write(coins_count: u64, groups: &[Vec<Coin>]) -> Vec<u8> {
+        let mut buf = vec![];
+        let metadata = SnapshotMetadata {
+            network_magic: MAGIC,
+            base_blockhash: BlockHash::from_byte_array([0xbb; 32]),
+            coins_count,
+        };
+        metadata.serialize(&mut buf).unwrap();
+        for group in groups {
+            write_group(&mut buf, group).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn roundtrip() {
+        let groups = groups();
+        let coins: Vec<Coin> = groups.concat();
+        assert!(groups.iter().all(|group| group.len() > 1));
+        let buf = write(coins.len() as u64, &groups);
+
+        let reader = SnapshotReader::new(&buf[..]).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.network_magic, MAGIC);
+        assert_eq!(metadata.base_blockhash.to_byte_array(), [0xbb; 32]);
+        assert_eq!(metadata.coins_count, coins.len() as u64);
+        let read: Vec<Coin> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, coins);
+    }
+
+    #[test]
+    fn trailing_data() {
+        let groups = groups();
+        let count = groups.concat().len();
+        let mut buf = write(count as u64, &groups);
+        buf.push(0);
+
+        let results: Vec<_> = SnapshotReader::new(&buf[..]).unwrap().collect();
+        assert_eq!(results.len(), count + 1);
+        assert!(results[..count].iter().all(Result::is_ok));
+        assert!(matches!(
+            results[count],
+            Err(ScannerError::CorruptRecord(_))
+        ));
+    }
+
+    #[test]
+    fn group_larger_than_count() {
+        let groups = groups();
+        let buf = write(groups[0].len() as u64 - 1, &groups[..1]);
+
+        let mut reader = SnapshotReader::new(&buf[..]).unwrap();
+        assert!(matches!(
+            reader.next(),
+            Some(Err(ScannerError::CorruptRecord(_)))
+        ));
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::ErrorKind,
+    ops::{Deref, Range},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use memmap2::Mmap;
+
+use crate::{ScannerError, XorKey, MAX_SIZE};
+
+/// Memory-mapped blk/rev files of a datadir, mapped on first use and kept
+/// open. Share it between threads with an `Arc`.
+///
+/// Every read checks the file's length first and remaps it if it changed,
+/// a mapping is never read past the end of its file.
+pub struct BlockFileStore {
+    blocks_dir: PathBuf,
+    magic: [u8; 4],
+    xor_key: XorKey,
+    maps: RwLock<HashMap<(&'static str, u32), Arc<Mmap>>>,
+}
+
+/// The payload of a blk/rev record. Borrowed from the mapped file unless the
+/// file is XOR-obfuscated, then de-obfuscated into a copy.
+pub struct RecordBytes(Inner);
+
+enum Inner {
+    Mapped(Arc<Mmap>, Range<usize>),
+    Owned(Vec<u8>),
+}
+
+impl Deref for RecordBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Inner::Mapped(map, range) => &map[range.clone()],
+            Inner::Owned(buf) => buf,
+        }
+    }
+}
+
+impl BlockFileStore {
+    pub fn new(blocks_dir: PathBuf, magic: [u8; 4], xor_key: XorKey) -> Self {
+        Self {
+            blocks_dir,
+            magic,
+            xor_key,
+            maps: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the block whose payload starts at `offset` in blk file `file`.
+    pub fn block_record(&self, file: u32, offset: u64) -> Result<RecordBytes, ScannerError> {
+        self.read_record("blk", file, offset, 0)
+    }
+
+    /// Reads the undo data at `offset` in rev file `file`, including the
+    /// 32 byte checksum following it.
+    pub fn undo_record(&self, file: u32, offset: u64) -> Result<RecordBytes, ScannerError> {
+        self.read_record("rev", file, offset, 32)
+    }
+
+    /// Reads the record whose payload starts at `offset`, checking the magic
+    /// and size prefix. Returns the payload followed by `extra` bytes.
+    fn read_record(
+        &self,
+        prefix: &'static str,
+        file: u32,
+        offset: u64,
+        extra: usize,
+    ) -> Result<RecordBytes, ScannerError> {
+        let path = self.path(prefix, file);
+        if offset < 8 {
+            return Err(ScannerError::CorruptRecord(format!(
+                "record offset {} in {}",
+                offset,
+                path.display()
+            )));
+        }
+        let start = offset as usize - 8;
+
+        // the file may have grown since we mapped it, or been truncated when
+        // bitcoind moved on to the next file
+        let file_len = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(ScannerError::PrunedFile(path))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut map = self.map(prefix, file, false)?;
+        if map.len() as u64 != file_len {
+            map = self.map(prefix, file, true)?;
+        }
+        // touching a mapping past the end of its file raises SIGBUS
+        let available = (map.len() as u64).min(file_len) as usize;
+
+        if available < start + 8 {
+            return Err(ScannerError::CorruptRecord(format!(
+                "offset {} past end of {}",
+                offset,
+                path.display()
+            )));
+        }
+        let mut magic_size = [0; 8];
+        magic_size.copy_from_slice(&map[start..start + 8]);
+        self.xor_key.apply(&mut magic_size, start as u64);
+
+        let magic: [u8; 4] = magic_size[..4].try_into().unwrap();
+        if magic != self.magic {
+            return Err(ScannerError::BadMagic {
+                path,
+                offset: offset - 8,
+                found: magic,
+            });
+        }
+        let size = u32::from_le_bytes(magic_size[4..8].try_into().unwrap()) as u64;
+        if size > MAX_SIZE {
+            return Err(ScannerError::CorruptRecord(format!(
+                "record size {} at {} in {}",
+                size,
+                offset,
+                path.display()
+            )));
+        }
+
+        let range = offset as usize..offset as usize + size as usize + extra;
+        if available < range.end {
+            return Err(ScannerError::CorruptRecord(format!(
+                "record at {} runs past end of {}",
+                offset,
+                path.display()
+            )));
+        }
+
+        if self.xor_key.is_zero() {
+            return Ok(RecordBytes(Inner::Mapped(map, range)));
+        }
+        let mut buf = map[range].to_vec();
+        self.xor_key.apply(&mut buf, offset);
+        Ok(RecordBytes(Inner::Owned(buf)))
+    }
+
+    /// Returns the mapping of a file, mapping it if it isn't yet or `remap` is set.
+    fn map(&self, prefix: &'static str, file: u32, remap: bool) -> Result<Arc<Mmap>, ScannerError> {
+        if !remap {
+            if let Some(map) = self.maps.read().unwrap().get(&(prefix, file)) {
+                return Ok(map.clone());
+            }
+        }
+
+        let path = self.path(prefix, file);
+        let f = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(ScannerError::PrunedFile(path))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // SAFETY: bitcoind appends to blk/rev files, truncates their
+        // preallocated tail once it moves on to the next file and deletes
+        // them when pruning. Reads stay below the file's current length, in
+        // records the block index points to, which none of these touch.
+        let map = Arc::new(unsafe { Mmap::map(&f)? });
+        self.maps
+            .write()
+            .unwrap()
+            .insert((prefix, file), map.clone());
+        Ok(map)
+    }
+
+    fn path(&self, prefix: &str, file: u32) -> PathBuf {
+        self.blocks_dir.join(format!("{}{:05}.dat", prefix, file))
+    }
+}
@@ -0,0 +1,84 @@
+use bitcoin::{
+    absolute::LockTime,
+    blockdata::{block::Header, block::Version, constants::genesis_block},
+    hash_types::TxMerkleNode,
+    hashes::Hash,
+    Block, BlockHash, CompactTarget, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Witness,
+};
+
+/// The magic of testnet4 (BIP94) block files. `bitcoin` 0.30 has no network
+/// for it, testnet4 datadirs are opened with [`Network::Testnet`] and told
+/// apart from testnet3 by this magic.
+pub const TESTNET4_MAGIC: [u8; 4] = [0x1c, 0x16, 0x3f, 0x28];
+
+const TESTNET4_TIMESTAMP: &[u8] =
+    b"03/May/2024 000000000000000000001ebd58c244970b3aa9d783bb001011fbe8ea8e98e00e";
+
+/// The genesis block of testnet4, as in Bitcoin Core's chainparams.
+pub fn testnet4_genesis_block() -> Block {
+    // nBits 486604799, CScriptNum(4) and the timestamp, which needs OP_PUSHDATA1
+    let script_sig = [
+        &[0x04, 0xff, 0xff, 0x00, 0x1d, 0x01, 0x04, 0x4c][..],
+        &[TESTNET4_TIMESTAMP.len() as u8],
+        TESTNET4_TIMESTAMP,
+    ]
+    .concat();
+    // a pay-to-pubkey to 33 zero bytes
+    let script_pubkey = [&[33][..], &[0; 33], &[0xac]].concat();
+
+    let coinbase = Transaction {
+        version: 1,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::from(script_sig),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 50 * 100_000_000,
+            script_pubkey: ScriptBuf::from(script_pubkey),
+        }],
+    };
+
+    Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::from_raw_hash(coinbase.txid().to_raw_hash()),
+            time: 1714777860,
+            bits: CompactTarget::from_consensus(0x1d00ffff),
+            nonce: 393743547,
+        },
+        txdata: vec![coinbase],
+    }
+}
+
+/// The chains `network` can stand for, as the magic of their block files
+/// and their genesis block.
+pub(crate) fn chains(network: Network) -> Vec<([u8; 4], Block)> {
+    let mut chains = vec![(network.magic().to_bytes(), genesis_block(network))];
+    if network == Network::Testnet {
+        chains.push((TESTNET4_MAGIC, testnet4_genesis_block()));
+    }
+    chains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_hash() {
+        let genesis = testnet4_genesis_block();
+        assert_eq!(
+            genesis.header.merkle_root.to_string(),
+            "7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e"
+        );
+        assert_eq!(
+            genesis.block_hash().to_string(),
+            "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"
+        );
+    }
+}
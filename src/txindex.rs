@@ -0,0 +1,96 @@
+use bitcoin::{
+    blockdata::block::Header, consensus::Decodable, hashes::Hash, BlockHash, Transaction, Txid,
+};
+
+use crate::{index::IndexDb, read_varint_core, Scanner, ScannerError};
+
+/// Position of a transaction in the blk files, from a `t` record of the txindex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionIndexRecord {
+    pub block_file_number: u32,
+    /// Offset of the block (after its magic and size) in the blk file.
+    pub block_offset: u64,
+    /// Offset of the transaction counted from the end of the block header.
+    pub tx_offset: u64,
+}
+
+impl TransactionIndexRecord {
+    fn parse(value: &[u8]) -> Result<Self, ScannerError> {
+        let corrupt = |_| ScannerError::CorruptRecord("truncated txindex record".into());
+
+        let mut r = value;
+        let block_file_number = read_varint_core(&mut r).map_err(corrupt)? as u32;
+        let block_offset = read_varint_core(&mut r).map_err(corrupt)?;
+        let tx_offset = read_varint_core(&mut r).map_err(corrupt)?;
+
+        Ok(Self {
+            block_file_number,
+            block_offset,
+            tx_offset,
+        })
+    }
+}
+
+impl Scanner {
+    /// Looks up the position of a transaction in the txindex, opening
+    /// `indexes/txindex` on first use.
+    pub fn transaction_index_record(
+        &mut self,
+        txid: &Txid,
+    ) -> Result<Option<TransactionIndexRecord>, ScannerError> {
+        if self.txindex.is_none() {
+            let path = self.datadir.join("indexes").join("txindex");
+            self.txindex = Some(IndexDb::open(path, self.copy.as_ref())?);
+        }
+        let txindex = self.txindex.as_mut().unwrap();
+
+        let key = [&b"t"[..], &txid.to_byte_array()[..]].concat();
+        match txindex.get(&key) {
+            Some(value) => TransactionIndexRecord::parse(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a transaction through Bitcoin Core's `-txindex`, along with the
+    /// hash and height of the block containing it. `None` if the txid is not
+    /// indexed.
+    pub fn read_transaction(
+        &mut self,
+        txid: &Txid,
+    ) -> Result<Option<(Transaction, BlockHash, u32)>, ScannerError> {
+        let record = match self.transaction_index_record(txid)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+
+        let bytes = self
+            .block_files
+            .block_record(record.block_file_number, record.block_offset)?;
+        let header = Header::consensus_decode(&mut &bytes[..])?;
+        let mut tx_bytes = usize::try_from(record.tx_offset)
+            .ok()
+            .and_then(|tx_offset| bytes.get(80 + tx_offset..))
+            .ok_or_else(|| {
+                ScannerError::CorruptRecord(format!(
+                    "txindex offset {} past the end of block {}",
+                    record.tx_offset,
+                    header.block_hash()
+                ))
+            })?;
+        let tx = Transaction::consensus_decode(&mut tx_bytes)?;
+
+        if tx.txid() != *txid {
+            return Err(ScannerError::CorruptRecord(format!(
+                "txindex points to {} instead of {}",
+                tx.txid(),
+                txid
+            )));
+        }
+
+        // refuses blocks above the snapshot tip
+        let block_hash = header.block_hash();
+        let index_record = self.block_index_record(&block_hash)?;
+        self.record_locations(&index_record)?;
+        Ok(Some((tx, block_hash, index_record.height)))
+    }
+}
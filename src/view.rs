@@ -0,0 +1,467 @@
+use std::io::{self, ErrorKind};
+
+use bitcoin::{
+    blockdata::block::Header,
+    consensus::deserialize,
+    hashes::{sha256d, Hash, HashEngine},
+    BlockHash, OutPoint, Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness, Wtxid,
+};
+
+use crate::{read_compact_size, ScannerError};
+
+/// A block over its serialized bytes, decoding only what is asked for.
+///
+/// Each transaction is walked once to find its boundaries. Its inputs,
+/// outputs and witnesses are only decoded when asked for. Read the bytes with
+/// [`Scanner::read_raw_block`](crate::Scanner::read_raw_block).
+#[derive(Debug, Clone, Copy)]
+pub struct BlockView<'a> {
+    bytes: &'a [u8],
+    header: Header,
+    tx_count: u64,
+    txs_start: usize,
+}
+
+impl<'a> BlockView<'a> {
+    /// Decodes the header and transaction count, nothing else is checked.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ScannerError> {
+        let mut r = Reader::new(bytes);
+        let header = deserialize(r.take(80)?)?;
+        let tx_count = r.compact_size()?;
+        Ok(Self {
+            bytes,
+            header,
+            tx_count,
+            txs_start: r.pos,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        self.header.block_hash()
+    }
+
+    pub fn tx_count(&self) -> u64 {
+        self.tx_count
+    }
+
+    /// Iterates the transactions, stopping at the first one that fails to decode.
+    pub fn transactions(&self) -> Transactions<'a> {
+        Transactions {
+            bytes: self.bytes,
+            pos: self.txs_start,
+            remaining: self.tx_count,
+        }
+    }
+}
+
+/// Iterator over the transactions of a [`BlockView`].
+pub struct Transactions<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    remaining: u64,
+}
+
+impl<'a> Iterator for Transactions<'a> {
+    type Item = Result<TxView<'a>, ScannerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match TxView::parse(self.bytes, self.pos) {
+            Ok(tx) => {
+                self.pos += tx.bytes.len();
+                self.remaining -= 1;
+                Some(Ok(tx))
+            }
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, usize::try_from(self.remaining).ok())
+    }
+}
+
+/// A transaction within a block's bytes, with the positions of its parts.
+#[derive(Debug, Clone, Copy)]
+pub struct TxView<'a> {
+    /// The whole transaction, including witness data.
+    bytes: &'a [u8],
+    offset: usize,
+    has_witness: bool,
+    input_count: u64,
+    /// Start of the input count, past the segwit marker and flag.
+    inputs_start: usize,
+    output_count: u64,
+    outputs_start: usize,
+    /// End of the outputs, where the witnesses or the lock time begin.
+    outputs_end: usize,
+}
+
+impl<'a> TxView<'a> {
+    /// Walks the transaction starting at `offset` in `block` to find its end.
+    fn parse(block: &'a [u8], offset: usize) -> Result<Self, ScannerError> {
+        let mut r = Reader::new(&block[offset..]);
+        r.take(4)?;
+
+        // BIP144: an empty input list followed by a flag means witness data
+        let mut has_witness = false;
+        let mut inputs_start = r.pos;
+        if r.peek(2)? == [0, 1] {
+            r.take(2)?;
+            has_witness = true;
+            inputs_start = r.pos;
+        }
+
+        let input_count = r.compact_size()?;
+        for _ in 0..input_count {
+            r.take(36)?;
+            let script_len = r.compact_size()?;
+            r.take_u64(script_len)?;
+            r.take(4)?;
+        }
+
+        let outputs_start = r.pos;
+        let output_count = r.compact_size()?;
+        for _ in 0..output_count {
+            r.take(8)?;
+            let script_len = r.compact_size()?;
+            r.take_u64(script_len)?;
+        }
+        let outputs_end = r.pos;
+
+        if has_witness {
+            for _ in 0..input_count {
+                let items = r.compact_size()?;
+                for _ in 0..items {
+                    let len = r.compact_size()?;
+                    r.take_u64(len)?;
+                }
+            }
+        }
+        r.take(4)?;
+
+        Ok(Self {
+            bytes: &block[offset..offset + r.pos],
+            offset,
+            has_witness,
+            input_count,
+            inputs_start,
+            output_count,
+            outputs_start,
+            outputs_end,
+        })
+    }
+
+    /// The serialized transaction, including witness data.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Position of the transaction within the block's bytes.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.has_witness
+    }
+
+    pub fn version(&self) -> i32 {
+        i32::from_le_bytes(self.bytes[..4].try_into().unwrap())
+    }
+
+    pub fn lock_time(&self) -> u32 {
+        let end = self.bytes.len();
+        u32::from_le_bytes(self.bytes[end - 4..].try_into().unwrap())
+    }
+
+    /// Hashes the transaction without its witness data, without allocating.
+    pub fn txid(&self) -> Txid {
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&self.bytes[..4]);
+        engine.input(&self.bytes[self.inputs_start..self.outputs_end]);
+        engine.input(&self.bytes[self.bytes.len() - 4..]);
+        Txid::from_engine(engine)
+    }
+
+    pub fn wtxid(&self) -> Wtxid {
+        Wtxid::hash(self.bytes)
+    }
+
+    pub fn input_count(&self) -> u64 {
+        self.input_count
+    }
+
+    pub fn output_count(&self) -> u64 {
+        self.output_count
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = InputView<'a>> + 'a {
+        let mut r = Reader::new(self.bytes);
+        r.pos = self.inputs_start;
+        // the layout was checked in TxView::parse
+        r.compact_size().unwrap();
+        (0..self.input_count).map(move |_| {
+            let start = r.pos;
+            r.take(36).unwrap();
+            let script_len = r.compact_size().unwrap();
+            let script_start = r.pos;
+            r.take_u64(script_len).unwrap();
+            r.take(4).unwrap();
+            InputView {
+                bytes: &r.bytes[start..r.pos],
+                script_start: script_start - start,
+            }
+        })
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = OutputView<'a>> + 'a {
+        let mut r = Reader::new(self.bytes);
+        r.pos = self.outputs_start;
+        // the layout was checked in TxView::parse
+        r.compact_size().unwrap();
+        (0..self.output_count).map(move |_| {
+            let start = r.pos;
+            r.take(8).unwrap();
+            let script_len = r.compact_size().unwrap();
+            let script_start = r.pos;
+            r.take_u64(script_len).unwrap();
+            OutputView {
+                bytes: &r.bytes[start..r.pos],
+                script_start: script_start - start,
+            }
+        })
+    }
+
+    /// The witnesses of all inputs, empty ones if the transaction has none.
+    pub fn witnesses(&self) -> Result<Vec<Witness>, ScannerError> {
+        if !self.has_witness {
+            return Ok(vec![Witness::new(); self.input_count as usize]);
+        }
+        let mut r = Reader::new(self.bytes);
+        r.pos = self.outputs_end;
+        let mut witnesses = Vec::with_capacity(self.input_count as usize);
+        for _ in 0..self.input_count {
+            let items = r.compact_size()?;
+            let mut witness = Witness::new();
+            for _ in 0..items {
+                let len = r.compact_size()?;
+                witness.push(r.take_u64(len)?);
+            }
+            witnesses.push(witness);
+        }
+        Ok(witnesses)
+    }
+
+    /// Decodes the whole transaction.
+    pub fn to_transaction(&self) -> Result<Transaction, ScannerError> {
+        Ok(deserialize(self.bytes)?)
+    }
+}
+
+/// A transaction input within a [`TxView`].
+#[derive(Debug, Clone, Copy)]
+pub struct InputView<'a> {
+    bytes: &'a [u8],
+    script_start: usize,
+}
+
+impl<'a> InputView<'a> {
+    pub fn previous_output(&self) -> OutPoint {
+        OutPoint {
+            txid: Txid::from_slice(&self.bytes[..32]).unwrap(),
+            vout: u32::from_le_bytes(self.bytes[32..36].try_into().unwrap()),
+        }
+    }
+
+    pub fn script_sig(&self) -> &'a Script {
+        Script::from_bytes(&self.bytes[self.script_start..self.bytes.len() - 4])
+    }
+
+    pub fn sequence(&self) -> Sequence {
+        let end = self.bytes.len();
+        Sequence(u32::from_le_bytes(
+            self.bytes[end - 4..].try_into().unwrap(),
+        ))
+    }
+
+    /// Decodes the input, without its witness.
+    pub fn to_txin(&self) -> TxIn {
+        TxIn {
+            previous_output: self.previous_output(),
+            script_sig: self.script_sig().to_owned(),
+            sequence: self.sequence(),
+            witness: Witness::new(),
+        }
+    }
+}
+
+/// A transaction output within a [`TxView`].
+#[derive(Debug, Clone, Copy)]
+pub struct OutputView<'a> {
+    bytes: &'a [u8],
+    script_start: usize,
+}
+
+impl<'a> OutputView<'a> {
+    pub fn value(&self) -> u64 {
+        u64::from_le_bytes(self.bytes[..8].try_into().unwrap())
+    }
+
+    pub fn script_pubkey(&self) -> &'a Script {
+        Script::from_bytes(&self.bytes[self.script_start..])
+    }
+
+    pub fn to_txout(&self) -> TxOut {
+        TxOut {
+            value: self.value(),
+            script_pubkey: self.script_pubkey().to_owned(),
+        }
+    }
+}
+
+/// Reads from a byte slice, failing like a decoder on truncated data.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn peek(&self, n: usize) -> Result<&'a [u8], ScannerError> {
+        self.bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(truncated)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ScannerError> {
+        let bytes = self.peek(n)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn take_u64(&mut self, n: u64) -> Result<&'a [u8], ScannerError> {
+        self.take(usize::try_from(n).map_err(|_| truncated())?)
+    }
+
+    fn compact_size(&mut self) -> Result<u64, ScannerError> {
+        let mut r = &self.bytes[self.pos..];
+        let n = read_compact_size(&mut r).map_err(|e| ScannerError::Decode(e.into()))?;
+        self.pos = self.bytes.len() - r.len();
+        Ok(n)
+    }
+}
+
+fn truncated() -> ScannerError {
+    ScannerError::Decode(io::Error::from(ErrorKind::UnexpectedEof).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime, blockdata::constants::genesis_block, consensus::serialize, Block,
+        Network, ScriptBuf,
+    };
+
+    use super::*;
+
+    /// Genesis with a segwit and a legacy transaction added.
+    fn block() -> Block {
+        let mut block = genesis_block(Network::Regtest);
+        let mut witness = Witness::new();
+        witness.push([1, 2, 3]);
+        witness.push([]);
+        let segwit = Transaction {
+            version: 2,
+            lock_time: LockTime::from_consensus(77),
+            input: vec![
+                TxIn {
+                    previous_output: OutPoint::new(block.txdata[0].txid(), 3),
+                    script_sig: ScriptBuf::from(vec![5, 6]),
+                    sequence: Sequence(9),
+                    witness,
+                },
+                TxIn {
+                    previous_output: OutPoint::new(block.txdata[0].txid(), 0),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                },
+            ],
+            output: vec![TxOut {
+                value: 1234,
+                script_pubkey: ScriptBuf::from(vec![0x51]),
+            }],
+        };
+        let mut legacy = segwit.clone();
+        legacy
+            .input
+            .iter_mut()
+            .for_each(|txin| txin.witness.clear());
+        legacy.output.push(TxOut {
+            value: 0,
+            script_pubkey: ScriptBuf::new(),
+        });
+        block.txdata.extend([segwit, legacy]);
+        block
+    }
+
+    #[test]
+    fn matches_full_decoding() {
+        let block = block();
+        let bytes = serialize(&block);
+        let view = BlockView::new(&bytes).unwrap();
+        assert_eq!(view.block_hash(), block.block_hash());
+        assert_eq!(view.tx_count(), 3);
+
+        let txs: Vec<_> = view.transactions().collect::<Result<_, _>>().unwrap();
+        let mut offset = 80 + 1;
+        for (tx_view, tx) in txs.iter().zip(&block.txdata) {
+            let tx_bytes = serialize(tx);
+            assert_eq!(tx_view.offset(), offset);
+            assert_eq!(&bytes[offset..offset + tx_bytes.len()], tx_view.bytes());
+            offset += tx_bytes.len();
+
+            assert_eq!(tx_view.txid(), tx.txid());
+            assert_eq!(tx_view.wtxid(), tx.wtxid());
+            assert_eq!(tx_view.version(), tx.version);
+            assert_eq!(tx_view.lock_time(), tx.lock_time.to_consensus_u32());
+            assert_eq!(&tx_view.to_transaction().unwrap(), tx);
+
+            let inputs: Vec<_> = tx_view.inputs().map(|input| input.to_txin()).collect();
+            let witnesses = tx_view.witnesses().unwrap();
+            assert_eq!(inputs.len(), tx.input.len());
+            for ((input, witness), txin) in inputs.into_iter().zip(witnesses).zip(&tx.input) {
+                assert_eq!(TxIn { witness, ..input }, *txin);
+            }
+            let outputs: Vec<_> = tx_view.outputs().map(|output| output.to_txout()).collect();
+            assert_eq!(outputs, tx.output);
+        }
+        assert_eq!(offset, bytes.len());
+        assert!(!txs[0].has_witness() && txs[1].has_witness() && !txs[2].has_witness());
+    }
+
+    #[test]
+    fn truncated_block() {
+        let bytes = serialize(&block());
+        let view = BlockView::new(&bytes[..bytes.len() - 2]).unwrap();
+        let txs: Vec<_> = view.transactions().collect();
+        assert_eq!(txs.len(), 3);
+        assert!(txs[..2].iter().all(|tx| tx.is_ok()));
+        assert!(matches!(txs[2], Err(ScannerError::Decode(_))));
+
+        assert!(BlockView::new(&bytes[..50]).is_err());
+    }
+}
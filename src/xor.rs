@@ -0,0 +1,135 @@
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::ScannerError;
+
+/// Bitcoin Core 28+ XORs blk/rev files with the 8 byte key in `blocks/xor.dat`,
+/// repeating it from the start of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct XorKey(pub [u8; 8]);
+
+impl XorKey {
+    /// Reads `blocks/xor.dat`, datadirs of older versions have none and are
+    /// not obfuscated.
+    pub fn read(blocks_dir: &Path) -> Result<Self, ScannerError> {
+        let path = blocks_dir.join("xor.dat");
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let key = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            ScannerError::CorruptRecord(format!(
+                "{} has {} bytes, expected 8",
+                path.display(),
+                bytes.len()
+            ))
+        })?;
+        Ok(Self(key))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 8]
+    }
+
+    /// XORs `buf` in place, `offset` being the position of `buf[0]` in its file.
+    pub fn apply(&self, buf: &mut [u8], offset: u64) {
+        if self.is_zero() {
+            return;
+        }
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte ^= self.0[((offset + i as u64) % 8) as usize];
+        }
+    }
+}
+
+/// Reads a blk/rev file, de-obfuscating on the fly. Seeking is passed
+/// through, the key position follows the position in the file.
+pub struct XorReader<R> {
+    inner: R,
+    key: XorKey,
+    pos: u64,
+}
+
+impl<R> XorReader<R> {
+    /// `inner` must be positioned at the start of the file.
+    pub fn new(inner: R, key: XorKey) -> Self {
+        Self { inner, key, pos: 0 }
+    }
+}
+
+impl XorReader<io::BufReader<File>> {
+    /// Moves by `offset` bytes without discarding the read buffer.
+    pub fn seek_relative(&mut self, offset: i64) -> io::Result<()> {
+        self.inner.seek_relative(offset)?;
+        self.pos = self.inner.stream_position()?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.key.apply(&mut buf[..n], self.pos);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for XorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::BufReader};
+
+    use super::*;
+    use crate::BlockFileStore;
+
+    const KEY: XorKey = XorKey([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+    const MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    fn record(payload: &[u8]) -> Vec<u8> {
+        [&MAGIC[..], &(payload.len() as u32).to_le_bytes(), payload].concat()
+    }
+
+    #[test]
+    fn reads_back_obfuscated_file() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-scanner-xor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // the second record starts at 13 and its payload at 21, neither a
+        // multiple of the key length
+        let payload: Vec<u8> = (0..100).collect();
+        let plain = [record(&[9; 5]), record(&payload)].concat();
+        let mut obfuscated = plain.clone();
+        KEY.apply(&mut obfuscated, 0);
+        assert_ne!(obfuscated, plain);
+        let path = dir.join("blk00000.dat");
+        fs::write(&path, &obfuscated).unwrap();
+
+        let mut reader = XorReader::new(BufReader::new(File::open(&path).unwrap()), KEY);
+        reader.seek(SeekFrom::Start(13)).unwrap();
+        let mut buf = vec![0; plain.len() - 13];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &plain[13..]);
+        reader.seek_relative(-90).unwrap();
+        let mut buf = [0; 3];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, plain[plain.len() - 90..][..3]);
+
+        let store = BlockFileStore::new(dir.clone(), MAGIC, KEY);
+        assert_eq!(&store.block_record(0, 8).unwrap()[..], &[9; 5]);
+        assert_eq!(&store.block_record(0, 21).unwrap()[..], &payload[..]);
+        assert!(store.block_record(0, 20).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}